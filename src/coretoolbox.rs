@@ -1,11 +1,8 @@
-use directories;
-use failure::{bail, Fallible};
+use failure::{bail, Fallible, ResultExt};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use signal_hook;
 use std::io::prelude::*;
-use std::os::unix::process::CommandExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::Path;
 use std::process::{Command, Stdio};
 use structopt::StructOpt;
@@ -13,13 +10,352 @@ use structopt::StructOpt;
 lazy_static! {
     static ref APPDIRS: directories::ProjectDirs =
         directories::ProjectDirs::from("com", "coreos", "toolbox").expect("creating appdirs");
+    /// The configuration file, loaded on first use.  Errors are kept
+    /// as strings so they can be reported from `config()`.
+    static ref CONFIG: Result<Config, String> = Config::load().map_err(|e| e.to_string());
+    /// The toolbox image matching the host's release, if we know one.
+    static ref HOST_IMAGE: Option<String> = host_image();
+    /// Container name used when none is specified.
+    static ref DEFAULT_CONTAINER_NAME: String = match CONFIG.as_ref().ok().and_then(|c| c.name.clone()) {
+        Some(name) => name,
+        None => default_container_name(&resolve_image(default_image())),
+    };
 }
 
+/// The number of subordinate ids `doctor` recommends; we use
+/// whatever is allocated in /etc/subuid and /etc/subgid.
 static MAX_UID_COUNT: u32 = 65536;
 
+/// The first podman release with `--userns=keep-id`.
+static KEEP_ID_PODMAN_VERSION: (u32, u32, u32) = (1, 5, 0);
+
+/// The first podman release with `--userns=keep-id:size=N`; with
+/// older ones, `--userns-size` uses the manual mapping instead.
+static KEEP_ID_SIZE_PODMAN_VERSION: (u32, u32, u32) = (5, 0, 0);
+
+/// The first podman release with `--group-add=keep-groups`.
+static KEEP_GROUPS_PODMAN_VERSION: (u32, u32, u32) = (3, 2, 0);
+
+/// Set by `--offline`; see `offline()`.
+static OFFLINE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// How many times to try pulling from each source, doubling the
+/// delay (starting at a second) after each failure.
+static PULL_ATTEMPTS: u32 = 3;
+
+/// System-wide configuration, read before the user's.
+static SYSTEM_CONFIG: &str = "/etc/coretoolbox/config.toml";
+
+/// Name of the per-project configuration file, looked for in the
+/// working directory and its parents.
+static PROJECT_CONFIG: &str = ".coretoolbox.toml";
+
+/// The settings a PROJECT_CONFIG may have.  Checking out a repository
+/// shouldn't be enough to run code in the toolbox, where the host's
+/// home directory is, so the rest are ignored there: `env` could set
+/// e.g. BASH_ENV or LD_PRELOAD for every session, and `packages` run
+/// their scriptlets as root.
+static PROJECT_CONFIG_KEYS: &[&str] = &["image", "name", "volumes"];
+
+/// Settings read from SYSTEM_CONFIG, `config.toml` in the user's
+/// configuration directory and PROJECT_CONFIG, in that order; command
+/// line options take precedence.  Keys are kebab-case, but the
+/// snake_case spellings are accepted too.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    /// Image used when none is specified
+    image: Option<String>,
+    /// Container name used when none is specified
+    name: Option<String>,
+    /// Additional volumes for new containers, as for `podman --volume`
+    volumes: Vec<String>,
+    /// Host environment variables to forward, in addition to PRESERVED_ENV
+    #[serde(alias = "preserve_env")]
+    preserve_env: Vec<String>,
+    /// Environment variables not to forward, even if listed above
+    #[serde(alias = "unset_env")]
+    unset_env: Vec<String>,
+    /// Environment variables to set in the toolbox
+    env: std::collections::BTreeMap<String, String>,
+    /// Short names which can be used in place of an image
+    #[serde(alias = "image_aliases")]
+    image_aliases: std::collections::BTreeMap<String, String>,
+    /// Named setups, selected with `--profile`
+    profile: std::collections::BTreeMap<String, Profile>,
+    /// Packages to install in new containers
+    packages: Vec<String>,
+    /// Shell command run on the host before entering a toolbox
+    #[serde(alias = "pre_enter")]
+    pre_enter: Option<String>,
+    /// Shell commands run as the user in a toolbox each time it starts
+    #[serde(alias = "init_commands")]
+    init_commands: Vec<String>,
+    /// Interactive shell to start, instead of the host's $SHELL
+    #[serde(alias = "default_shell")]
+    default_shell: Option<String>,
+    /// Copy the proxy settings of the host's dnf.conf into new containers
+    #[serde(alias = "dnf_proxy")]
+    dnf_proxy: Option<bool>,
+    /// Passed verbatim to `podman create` for new containers
+    #[serde(alias = "extra_podman_args")]
+    extra_podman_args: Vec<String>,
+    /// When to pull the image for a new container
+    pull: Option<PullPolicy>,
+    /// Registries to pull from, in order, when pulling from the one
+    /// named by an image fails
+    #[serde(alias = "registry_mirrors")]
+    registry_mirrors: std::collections::BTreeMap<String, Vec<String>>,
+    /// Registry credentials file, as for `podman pull --authfile`
+    authfile: Option<String>,
+    /// Trust policy images must satisfy, see containers-policy.json(5)
+    #[serde(alias = "signature_policy")]
+    signature_policy: Option<String>,
+    /// Never contact a registry
+    offline: Option<bool>,
+    /// Directory of image archives to load from when offline
+    #[serde(alias = "image_archives")]
+    image_archives: Option<String>,
+    /// Let the user run anything with sudo without a password
+    #[serde(alias = "passwordless_sudo")]
+    passwordless_sudo: Option<bool>,
+    /// The user's password in toolboxes, as a crypt(3) hash
+    #[serde(alias = "password_hash")]
+    password_hash: Option<String>,
+    /// Lock the user's password in toolboxes
+    #[serde(alias = "lock_password")]
+    lock_password: Option<bool>,
+    /// Whether to install the interactive shell if the image lacks
+    /// it: "ask", "always" or "never"
+    #[serde(alias = "install_shell")]
+    install_shell: Option<InstallShell>,
+    /// Print a welcome message on first entering a toolbox
+    motd: Option<bool>,
+    /// Give new toolboxes access to the host's podman service
+    #[serde(alias = "podman_socket")]
+    podman_socket: Option<bool>,
+    /// Use the host's /etc/machine-id in new toolboxes
+    #[serde(alias = "share_machine_id")]
+    share_machine_id: Option<bool>,
+    /// Commands that run on the host when used in a toolbox
+    /// (default: HOST_COMMANDS)
+    #[serde(alias = "host_commands")]
+    host_commands: Option<Vec<String>>,
+}
+
+/// When to pull the image before creating a container.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PullPolicy {
+    /// Always pull, to get the latest version
+    Always,
+    /// Only if the image isn't present locally
+    Missing,
+    /// Never; fail if the image isn't present
+    Never,
+}
+
+fn parse_pull_policy(s: &str) -> Result<PullPolicy, String> {
+    match s {
+        "always" => Ok(PullPolicy::Always),
+        "missing" => Ok(PullPolicy::Missing),
+        "never" => Ok(PullPolicy::Never),
+        _ => Err(format!("Expected always, missing or never, not {}", s)),
+    }
+}
+
+/// What to do when the image lacks the interactive shell.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum InstallShell {
+    Ask,
+    Always,
+    Never,
+}
+
+impl InstallShell {
+    fn as_str(self) -> &'static str {
+        match self {
+            InstallShell::Ask => "ask",
+            InstallShell::Always => "always",
+            InstallShell::Never => "never",
+        }
+    }
+}
+
+/// A `[profile.NAME]` table in the configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Profile {
+    /// Image to use, unless one is given on the command line
+    image: Option<String>,
+    /// Additional volumes, on top of those configured globally
+    volumes: Vec<String>,
+    /// Environment variables to set
+    env: std::collections::BTreeMap<String, String>,
+    /// Packages to install, on top of those configured globally
+    packages: Vec<String>,
+}
+
+impl Config {
+    fn path() -> std::path::PathBuf {
+        APPDIRS.config_dir().join("config.toml")
+    }
+
+    /// Parse a configuration file, if it exists.
+    fn read(path: &Path) -> Fallible<Option<Config>> {
+        let buf = match std::fs::read_to_string(path) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(toml::from_str(&buf).with_context(|e| {
+            format!("Parsing {}: {}", path.display(), e)
+        })?))
+    }
+
+    /// Read a PROJECT_CONFIG, dropping the settings it may not have.
+    fn read_project(path: &Path) -> Fallible<Option<Config>> {
+        let buf = match std::fs::read_to_string(path) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut table: toml::value::Table = toml::from_str(&buf)
+            .with_context(|e| format!("Parsing {}: {}", path.display(), e))?;
+        let ignored: Vec<String> = table
+            .keys()
+            .filter(|k| !PROJECT_CONFIG_KEYS.contains(&k.as_str()))
+            .cloned()
+            .collect();
+        for k in &ignored {
+            eprintln!("warning: ignoring {} in {}", k, path.display());
+            table.remove(k);
+        }
+        Ok(Some(toml::Value::Table(table).try_into().with_context(|e| {
+            format!("Parsing {}: {}", path.display(), e)
+        })?))
+    }
+
+    /// Layer `other` on top: its values win, and lists are appended
+    /// so that system-wide volumes can't be dropped by users.
+    fn merge(&mut self, other: Config) {
+        if other.image.is_some() {
+            self.image = other.image;
+        }
+        if other.name.is_some() {
+            self.name = other.name;
+        }
+        self.volumes.extend(other.volumes);
+        self.preserve_env.extend(other.preserve_env);
+        self.unset_env.extend(other.unset_env);
+        self.env.extend(other.env);
+        self.image_aliases.extend(other.image_aliases);
+        self.profile.extend(other.profile);
+        self.packages.extend(other.packages);
+        self.init_commands.extend(other.init_commands);
+        if other.pre_enter.is_some() {
+            self.pre_enter = other.pre_enter;
+        }
+        if other.default_shell.is_some() {
+            self.default_shell = other.default_shell;
+        }
+        if other.dnf_proxy.is_some() {
+            self.dnf_proxy = other.dnf_proxy;
+        }
+        self.extra_podman_args.extend(other.extra_podman_args);
+        if other.pull.is_some() {
+            self.pull = other.pull;
+        }
+        self.registry_mirrors.extend(other.registry_mirrors);
+        if other.authfile.is_some() {
+            self.authfile = other.authfile;
+        }
+        if other.signature_policy.is_some() {
+            self.signature_policy = other.signature_policy;
+        }
+        if other.offline.is_some() {
+            self.offline = other.offline;
+        }
+        if other.image_archives.is_some() {
+            self.image_archives = other.image_archives;
+        }
+        if other.passwordless_sudo.is_some() {
+            self.passwordless_sudo = other.passwordless_sudo;
+        }
+        if other.password_hash.is_some() {
+            self.password_hash = other.password_hash;
+        }
+        if other.lock_password.is_some() {
+            self.lock_password = other.lock_password;
+        }
+        if other.install_shell.is_some() {
+            self.install_shell = other.install_shell;
+        }
+        if other.motd.is_some() {
+            self.motd = other.motd;
+        }
+        if other.host_commands.is_some() {
+            self.host_commands = other.host_commands;
+        }
+        if other.podman_socket.is_some() {
+            self.podman_socket = other.podman_socket;
+        }
+        if other.share_machine_id.is_some() {
+            self.share_machine_id = other.share_machine_id;
+        }
+    }
+
+    /// The nearest PROJECT_CONFIG, if any.
+    fn project_path() -> Option<std::path::PathBuf> {
+        let cwd = std::env::current_dir().ok()?;
+        cwd.ancestors()
+            .map(|d| d.join(PROJECT_CONFIG))
+            .find(|p| p.exists())
+    }
+
+    fn load() -> Fallible<Config> {
+        let mut config = Config::default();
+        for path in &[Path::new(SYSTEM_CONFIG).to_path_buf(), Config::path()] {
+            if let Some(c) = Config::read(path)? {
+                config.merge(c);
+            }
+        }
+        if let Some(path) = Config::project_path() {
+            if let Some(c) = Config::read_project(&path)? {
+                config.merge(c);
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The loaded configuration.
+fn config() -> Fallible<&'static Config> {
+    CONFIG.as_ref().map_err(|e| failure::err_msg(e.clone()))
+}
+
+/// Image used when none is configured and the host's release can't
+/// be matched to a toolbox image.
+static DEFAULT_IMAGE: &str = "registry.fedoraproject.org/f30/fedora-toolbox:30";
+
+/// Podman filter matching the containers and images we created.
+static TOOLBOX_FILTER: &str = "label=com.coreos.toolbox=true";
+/// Image labels identifying toolbox images; the latter is
+/// used by the upstream fedora-toolbox images.
+static TOOLBOX_IMAGE_LABELS: &[&str] = &["com.coreos.toolbox", "com.github.containers.toolbox"];
+
 /// Set of statically known paths to files/directories
 /// that we redirect inside the container to /host.
 static STATIC_HOST_FORWARDS: &[&str] = &["/run/dbus", "/run/libvirt"];
+
+/// Host commands which make no sense in a toolbox, run on the host
+/// instead by default.
+static HOST_COMMANDS: &[&str] = &["systemctl", "rpm-ostree", "flatpak", "journalctl"];
+
+/// Files podman generates for the container that we replace with the
+/// host's, since we share its network.
+static HOST_NETWORK_FILES: &[&str] = &["/etc/resolv.conf", "/etc/hosts"];
 /// Set of devices we forward (if they exist)
 static FORWARDED_DEVICES: &[&str] = &["bus", "dri", "kvm", "fuse"];
 
@@ -33,6 +369,7 @@ static PRESERVED_ENV: &[&str] = &[
     "SHELL",
     "SSH_AUTH_SOCK",
     "TERM",
+    "TZ",
     "VTE_VERSION",
     "XDG_CURRENT_DESKTOP",
     "XDG_DATA_DIRS",
@@ -44,10 +381,22 @@ static PRESERVED_ENV: &[&str] = &[
     "XDG_SESSION_TYPE",
     "XDG_VTNR",
     "WAYLAND_DISPLAY",
+    // Proxies, in both spellings since tools disagree
+    "http_proxy",
+    "https_proxy",
+    "ftp_proxy",
+    "all_proxy",
+    "no_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "FTP_PROXY",
+    "ALL_PROXY",
+    "NO_PROXY",
 ];
 
 trait CommandRunExt {
     fn run(&mut self) -> Fallible<()>;
+    fn run_get_output(&mut self) -> Fallible<String>;
 }
 
 impl CommandRunExt for Command {
@@ -58,293 +407,4067 @@ impl CommandRunExt for Command {
         }
         Ok(())
     }
+
+    /// Run the command and return its stdout as UTF-8.
+    fn run_get_output(&mut self) -> Fallible<String> {
+        let r = self.stderr(Stdio::inherit()).output()?;
+        if !r.status.success() {
+            bail!("Child [{:?}] exited: {}", self, r.status);
+        }
+        Ok(String::from_utf8(r.stdout)?)
+    }
 }
 
+/// The toolbox container a subcommand operates on, given either
+/// with `-c` or, as before, as a positional argument.
 #[derive(Debug, StructOpt)]
-struct RunOpts {
-    #[structopt(
-        short = "I",
-        long = "image",
-        default_value = "registry.fedoraproject.org/f30/fedora-toolbox:30"
-    )]
-    /// Use a different base image
-    image: String,
+struct ContainerOpts {
+    #[structopt(short = "c", long = "container", env = "CORETOOLBOX_CONTAINER")]
+    /// Name of the toolbox container (default: coretoolbox-$USER-RELEASE)
+    container: Option<String>,
 
-    #[structopt(
-        short = "n",
-        long = "name",
-        default_value = "coreos-toolbox"
-    )]
-    /// Name the container
-    name: String,
+    #[structopt(name = "NAME", conflicts_with = "container")]
+    /// Same as --container
+    name: Option<String>,
+}
 
-    #[structopt(short = "N", long = "nested")]
-    /// Allow running inside a container
-    nested: bool,
+impl ContainerOpts {
+    /// The container name, if one was given.  Only one of the two can
+    /// be given on the command line, but `container` may also come
+    /// from the environment.
+    fn get(&self) -> Option<&str> {
+        self.name.as_ref().or(self.container.as_ref()).map(|s| s.as_str())
+    }
 
-    #[structopt(short = "D", long = "destroy")]
-    /// Destroy any existing container
-    destroy: bool,
+    /// The container name, or the default.
+    fn name(&self) -> &str {
+        self.get().unwrap_or(&DEFAULT_CONTAINER_NAME)
+    }
 }
 
+/// Options for one toolbox session: how the host environment is
+/// passed in, and the shell.  These apply to one invocation only and
+/// aren't recorded in the container.
+#[derive(Debug, Default, StructOpt)]
+struct SessionOpts {
+    #[structopt(
+        short = "E",
+        long = "preserve-env",
+        env = "CORETOOLBOX_PRESERVE_ENV",
+        raw(number_of_values = "1", use_delimiter = "true")
+    )]
+    /// Also forward this host environment variable; may be repeated
+    preserve_env: Vec<String>,
 
-#[derive(Debug, StructOpt)]
-struct RmOpts {
     #[structopt(
-        short = "n",
-        long = "name",
-        default_value = "coreos-toolbox"
+        short = "e",
+        long = "env",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_env_assignment")
     )]
-    /// Name for container
-    name: String,
-}
+    /// Set KEY=VALUE in the toolbox; may be repeated
+    env: Vec<(String, String)>,
 
-#[derive(Debug, StructOpt)]
-#[structopt(name = "coretoolbox", about = "Toolbox")]
-#[structopt(rename_all = "kebab-case")]
-enum Opt {
-    /// Enter the toolbox
-    Run(RunOpts),
-    /// Delete the toolbox container
-    Rm(RmOpts),
-    /// Internal implementation detail; do not use
-    RunPid1,
-    /// Internal implementation detail; do not use
-    Exec,
+    #[structopt(
+        long = "unset-env",
+        env = "CORETOOLBOX_UNSET_ENV",
+        raw(number_of_values = "1", use_delimiter = "true")
+    )]
+    /// Don't forward this host environment variable; may be repeated
+    unset_env: Vec<String>,
+
+    #[structopt(long = "shell", env = "CORETOOLBOX_SHELL")]
+    /// Interactive shell to start (default: from the configuration, or $SHELL)
+    shell: Option<String>,
+
+    #[structopt(short = "q", long = "quiet")]
+    /// Don't print the welcome message on first entering a toolbox
+    quiet: bool,
 }
 
-fn cmd_podman() -> Command {
-    if let Some(podman) = std::env::var_os("podman") {
-        Command::new(podman)
-    } else {
-        Command::new("podman")
+fn parse_env_assignment(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err(format!("Expected KEY=VALUE, not {}", s)),
     }
 }
 
-/// Returns true if the host is OSTree based
-fn is_ostree_based_host() -> bool {
-    std::path::Path::new("/run/ostree-booted").exists()
-}
+/// Options for pulling images from a registry.
+#[derive(Debug, Clone, Default, StructOpt)]
+struct PullOpts {
+    #[structopt(long = "authfile", env = "REGISTRY_AUTH_FILE")]
+    /// Registry credentials file, as written by `podman login`
+    authfile: Option<String>,
 
-#[allow(dead_code)]
-enum InspectType {
-    Container,
-    Image,
-}
+    #[structopt(long = "signature-policy", env = "CORETOOLBOX_SIGNATURE_POLICY")]
+    /// Require images to satisfy this trust policy, instead of the
+    /// system's /etc/containers/policy.json
+    signature_policy: Option<String>,
 
-/// Returns true if an image or container is in the podman
-/// storage.
-fn podman_has(t: InspectType, name: &str) -> Fallible<bool> {
-    let typearg = match t {
-        InspectType::Container => "container",
-        InspectType::Image => "image",
-    };
-    Ok(cmd_podman()
-        .args(&["inspect", "--type", typearg, name])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()?
-        .success())
+    #[structopt(long = "arch", env = "CORETOOLBOX_ARCH")]
+    /// Use the image for another architecture, e.g. aarch64, run
+    /// with emulation
+    arch: Option<String>,
 }
 
-/// Pull a container image if not present
-fn ensure_image(name: &str) -> Fallible<()> {
-    if !podman_has(InspectType::Image, name)? {
-        cmd_podman().args(&["pull", name]).run()?;
+impl PullOpts {
+    /// The credentials file given, or the configured one.
+    fn authfile(&self) -> Option<&str> {
+        self.authfile
+            .as_deref()
+            .or_else(|| CONFIG.as_ref().ok().and_then(|c| c.authfile.as_deref()))
     }
-    Ok(())
-}
 
-/// Parse an extant environment variable as UTF-8
-fn getenv_required_utf8(n: &str) -> Fallible<String> {
-    if let Some(v) = std::env::var_os(n) {
-        Ok(v.to_str()
-            .ok_or_else(|| failure::format_err!("{} is invalid UTF-8", n))?
-            .to_string())
-    } else {
-        bail!("{} is unset", n)
+    /// The architecture given, in podman's (Go's) terms.
+    fn podman_arch(&self) -> Option<&str> {
+        self.arch.as_deref().map(podman_arch)
     }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct EntrypointState {
-    username: String,
-    uid: u32,
-    home: String,
-    ostree_based_host: bool,
-}
 
-fn append_preserved_env(c: &mut Command) -> Fallible<()> {
-    for n in PRESERVED_ENV.iter() {
-        let v = match std::env::var_os(n) {
-            Some(v) => v,
-            None => continue,
-        };
-        let v = v
-            .to_str()
-            .ok_or_else(|| failure::format_err!("{} contains invalid UTF-8", n))?;
-        c.arg(format!("--env={}={}", n, v));
+    /// The trust policy given, or the configured one.
+    fn signature_policy(&self) -> Option<&str> {
+        self.signature_policy
+            .as_deref()
+            .or_else(|| CONFIG.as_ref().ok().and_then(|c| c.signature_policy.as_deref()))
     }
-    Ok(())
 }
 
-fn create(opts: &RunOpts) -> Fallible<()> {
-    ensure_image(&opts.image)?;
+#[derive(Debug, Default, StructOpt)]
+struct CreateOpts {
+    #[structopt(short = "I", long = "image", env = "CORETOOLBOX_IMAGE")]
+    /// Use a different base image (default: from the configuration, or
+    /// the toolbox image for the host's release)
+    image: Option<String>,
 
-    if podman_has(InspectType::Container, &opts.name)? {
-        return Ok(());
-    }
+    #[structopt(
+        short = "c",
+        long = "container",
+        alias = "name",
+        env = "CORETOOLBOX_CONTAINER"
+    )]
+    /// Name the container (default: coretoolbox-$USER-RELEASE)
+    name: Option<String>,
 
-    // exec ourself as the entrypoint.  In the future this
-    // would be better with podman fd passing.
-    let self_bin = std::fs::read_link("/proc/self/exe")?;
-    let self_bin = self_bin
-        .as_path()
-        .to_str()
-        .ok_or_else(|| failure::err_msg("non-UTF8 self"))?;
+    #[structopt(short = "n", raw(hidden = "true"))]
+    /// Old spelling of -c, kept so existing scripts work
+    legacy_name: Option<String>,
 
-    let runtime_dir = getenv_required_utf8("XDG_RUNTIME_DIR")?;
-    let statefile = "coreos-toolbox.initdata";
+    #[structopt(short = "p", long = "profile", env = "CORETOOLBOX_PROFILE")]
+    /// Use a profile from the configuration
+    profile: Option<String>,
 
-    let mut podman = cmd_podman();
-    podman.args(&[
-        "create",
-        "--interactive",
-        "--tty",
-        "--hostname=toolbox",
-        "--network=host",
-        "--privileged",
-        "--security-opt=label=disable",
-        "--label=com.coreos.toolbox=true",
-        "--tmpfs=/run:rw"
-    ]);
-    podman.arg(format!("--name={}", opts.name));
-    podman.arg(format!("--volume={}:/usr/bin/toolbox:ro", self_bin));
-    let real_uid: u32 = nix::unistd::getuid().into();
-    // In true privileged mode we don't use userns
-    if real_uid != 0 {
-        let uid_plus_one = real_uid + 1;
-        let max_minus_uid = MAX_UID_COUNT - real_uid;
-        podman.args(&[
-            format!("--uidmap={}:0:1", real_uid),
-            format!("--uidmap=0:1:{}", real_uid),
-            format!(
-                "--uidmap={}:{}:{}",
-                uid_plus_one, uid_plus_one, max_minus_uid
-            ),
-        ]);
-    }
+    #[structopt(long = "env-file", env = "CORETOOLBOX_ENV_FILE", parse(from_os_str))]
+    /// Keep KEY=VALUE lines from this file to set in every session
+    env_file: Option<std::path::PathBuf>,
 
-    for p in &["/dev", "/usr", "/var", "/etc", "/run", "/tmp"] {
-        podman.arg(format!("--volume={}:/host{}:rslave", p, p));
-    }
-    if is_ostree_based_host() {
-        podman.arg(format!("--volume=/sysroot:/host/sysroot:rslave"));
-    } else {
-        for p in &["/media", "/mnt", "/home", "/srv"] {
-            podman.arg(format!("--volume={}:/host{}:rslave", p, p));
-        }
-    }
-    append_preserved_env(&mut podman)?;
-    podman.arg(format!("--env=TOOLBOX_STATEFILE={}", statefile));
+    #[structopt(short = "v", long = "volume", raw(number_of_values = "1"))]
+    /// Add a bind mount, as for `podman --volume` (repeatable)
+    volumes: Vec<String>,
 
-    {
-        let state = EntrypointState {
-            username: getenv_required_utf8("USER")?,
-            uid: real_uid,
-            home: getenv_required_utf8("HOME")?,
-            ostree_based_host: is_ostree_based_host(),
-        };
-        let w = std::fs::File::create(format!("{}/{}", runtime_dir, statefile))?;
-        let mut w = std::io::BufWriter::new(w);
-        serde_json::to_writer(&mut w, &state)?;
-        w.flush()?;
-    }
+    #[structopt(long = "device", raw(number_of_values = "1"))]
+    /// Forward a host device, e.g. /dev/ttyUSB0, in addition to the
+    /// default set (repeatable); it need not exist yet
+    devices: Vec<String>,
 
-    podman.arg(&opts.image);
-    podman.args(&["/usr/bin/toolbox", "run-pid1"]);
-    podman.stdout(Stdio::null());
-    podman.run()?;
-    Ok(())
-}
+    #[structopt(
+        long = "podman-arg",
+        raw(number_of_values = "1", allow_hyphen_values = "true")
+    )]
+    /// Pass an extra argument to `podman create` (repeatable)
+    podman_args: Vec<String>,
 
-fn in_container() -> bool {
-    Path::new("/run/.containerenv").exists()
+    #[structopt(long = "isolated-home")]
+    /// Give the toolbox its own home directory, populated from the
+    /// image's /etc/skel, instead of sharing the host's
+    isolated_home: bool,
+
+    #[structopt(long = "home-volume", env = "CORETOOLBOX_HOME_VOLUME")]
+    /// Podman volume for the isolated home directory (implies
+    /// --isolated-home; default: NAME-home)
+    home_volume: Option<String>,
+
+    #[structopt(long = "podman-socket")]
+    /// Let podman in the toolbox use the host's podman service
+    podman_socket: bool,
+
+    #[structopt(long = "userns-size")]
+    /// Number of ids in the toolbox's user namespace, including our own
+    /// (default: all our subordinate ids)
+    userns_size: Option<u32>,
+
+    #[structopt(
+        long = "pull",
+        env = "CORETOOLBOX_PULL",
+        parse(try_from_str = "parse_pull_policy"),
+        raw(possible_values = "&[\"always\", \"missing\", \"never\"]")
+    )]
+    /// Pull the image always, if missing (the default), or never
+    pull: Option<PullPolicy>,
+
+    #[structopt(flatten)]
+    pullopts: PullOpts,
+
+    #[structopt(long = "show-command", alias = "dry-run")]
+    /// Print the podman commands instead of running them
+    show_command: bool,
 }
 
-fn run(opts: &RunOpts) -> Fallible<()> {
-    if in_container() && !opts.nested {
-        bail!("Already inside a container");
+impl CreateOpts {
+    /// The selected profile, if any.
+    fn profile(&self) -> Fallible<Option<&'static Profile>> {
+        match self.profile {
+            Some(ref name) => match config()?.profile.get(name) {
+                Some(p) => Ok(Some(p)),
+                None => bail!("No profile {} in the configuration", name),
+            },
+            None => Ok(None),
+        }
     }
 
-    if opts.destroy {
-        rm(&RmOpts { name: opts.name.clone() })?;
+    /// The image given, or the profile's, or the default, with aliases
+    /// resolved.
+    fn image(&self) -> String {
+        let profile = self.profile().ok().flatten();
+        let image = self
+            .image
+            .as_deref()
+            .or_else(|| profile.and_then(|p| p.image.as_deref()))
+            .unwrap_or_else(|| default_image());
+        resolve_image(image)
     }
 
-    create(&opts)?;
+    /// Options to create `name` again from `image`, as it was
+    /// originally created.
+    fn recreate(name: &str, image: &str) -> Fallible<CreateOpts> {
+        Ok(CreateOpts {
+            image: Some(image.to_string()),
+            name: Some(name.to_string()),
+            profile: container_profile(name)?,
+            devices: container_list_label(name, "com.coreos.toolbox.devices")?,
+            podman_args: container_list_label(name, "com.coreos.toolbox.podman-args")?,
+            home_volume: container_home_volume(name)?,
+            podman_socket: container_label(name, "com.coreos.toolbox.podman-socket")? == "true",
+            userns_size: container_label(name, "com.coreos.toolbox.userns-size")?.parse().ok(),
+            // The caller has just pulled it; but with a trust policy
+            // it has to be pulled again to be verified.
+            pull: Some(PullPolicy::Missing),
+            pullopts: PullOpts {
+                arch: container_arch(name)?,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
 
-    cmd_podman()
-        .args(&["start", opts.name.as_str()])
-        .stdout(Stdio::null())
-        .run()?;
+    /// The volume holding the isolated home directory, if any.
+    fn home_volume(&self) -> Option<String> {
+        match self.home_volume {
+            Some(ref v) => Some(v.clone()),
+            None if self.isolated_home => Some(format!("{}-home", self.container_name())),
+            None => None,
+        }
+    }
 
-    let mut podman = cmd_podman();
-    podman.args(&["exec", "--interactive", "--tty"]);
-    append_preserved_env(&mut podman)?;
-    podman.args(&[opts.name.as_str(), "/usr/bin/toolbox", "exec"]);
-    return Err(podman.exec().into());
-}
+    /// The pull policy given, or the configured one.
+    fn pull_policy(&self) -> PullPolicy {
+        self.pull
+            .or_else(|| CONFIG.as_ref().ok().and_then(|c| c.pull))
+            .unwrap_or(PullPolicy::Missing)
+    }
 
-fn rm(opts: &RmOpts) -> Fallible<()> {
-    if !podman_has(InspectType::Container, opts.name.as_str())? {
-        return Ok(());
+    /// The container name given, or the configured one, or the
+    /// per-user default for the image.
+    fn container_name(&self) -> String {
+        let name = self.legacy_name.as_ref().or(self.name.as_ref());
+        match (name, CONFIG.as_ref().ok().and_then(|c| c.name.as_ref())) {
+            (Some(name), _) | (None, Some(name)) => name.clone(),
+            (None, None) => match self.pullopts.podman_arch() {
+                // So it doesn't clash with the native one.
+                Some(arch) if arch != podman_arch(std::env::consts::ARCH) => {
+                    format!("{}-{}", default_container_name(&self.image()), arch)
+                }
+                _ => default_container_name(&self.image()),
+            },
+        }
     }
-    let mut podman = cmd_podman();
-    podman
-        .args(&["rm", "-f", opts.name.as_str()])
-        .stdout(Stdio::null());
-    Err(podman.exec().into())
 }
 
-fn run_pid1(_opts: Opt) -> Fallible<()> {
-    unsafe {
-        signal_hook::register(signal_hook::SIGCHLD, waitpid_all)?;
-        signal_hook::register(signal_hook::SIGTERM, || std::process::exit(0))?;
-    };
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(1_000_000));
+/// Translate common architecture names, as in `uname -m`, into
+/// those podman uses.
+fn podman_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "armv7l" | "armhfp" => "arm",
+        "i686" | "i386" => "386",
+        a => a,
     }
 }
 
-fn waitpid_all() {
-    use nix::sys::wait::WaitStatus;
-    loop {
-        match nix::sys::wait::waitpid(None, Some(nix::sys::wait::WaitPidFlag::WNOHANG)) {
-            Ok(status) => {
-                match status {
-                    WaitStatus::StillAlive => break,
-                    _ => {},
-                }
-            }
-            Err(_) => break,
-        }
-    }
+/// The architecture a container was created for, if not the host's.
+fn container_arch(name: &str) -> Fallible<Option<String>> {
+    let v = container_label(name, "com.coreos.toolbox.arch")?;
+    Ok(if v.is_empty() { None } else { Some(v) })
 }
 
-mod entrypoint {
-    use super::CommandRunExt;
-    use super::EntrypointState;
-    use failure::{bail, Fallible, ResultExt};
+/// Warn if running `arch` binaries needs emulation, and fail if
+/// there's none.
+fn check_foreign_arch(arch: &str) -> Fallible<()> {
+    if arch == podman_arch(std::env::consts::ARCH) {
+        return Ok(());
+    }
+    // qemu-user-static names its binfmt_misc handlers for uname -m.
+    let qemu_arch = match arch {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "386" => "i386",
+        a => a,
+    };
+    let handler = format!("/proc/sys/fs/binfmt_misc/qemu-{}", qemu_arch);
+    match std::fs::read_to_string(&handler) {
+        Ok(ref h) if h.starts_with("enabled") => {}
+        _ => bail!(
+            "No emulation for {} binaries ({} is missing or disabled); install qemu-user-static",
+            arch,
+            handler
+        ),
+    }
+    eprintln!(
+        "warning: {} binaries will run under emulation, which is much slower",
+        arch
+    );
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+struct CreateCmdOpts {
+    #[structopt(flatten)]
+    create: CreateOpts,
+
+    #[structopt(long = "start")]
+    /// Also start and initialize the container in the background
+    start: bool,
+
+    #[structopt(name = "PODMAN_ARGS", raw(last = "true"))]
+    /// Extra arguments for `podman create`, after `--`
+    trailing_podman_args: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct RunOpts {
+    #[structopt(flatten)]
+    create: CreateOpts,
+
+    #[structopt(short = "N", long = "nested")]
+    /// Allow running inside a container
+    nested: bool,
+
+    #[structopt(short = "D", long = "destroy")]
+    /// Destroy any existing container
+    destroy: bool,
+
+    #[structopt(short = "d", long = "detach")]
+    /// Start and initialize the container in the background instead
+    detach: bool,
+
+    #[structopt(flatten)]
+    session: SessionOpts,
+
+    #[structopt(raw(allow_hyphen_values = "true"))]
+    /// Command to run inside the toolbox; if unset, start an interactive shell
+    command: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct EnterOpts {
+    // If no container is given and there are several, enter()
+    // lets the user choose one interactively.
+    #[structopt(flatten)]
+    container: ContainerOpts,
+
+    #[structopt(long = "root")]
+    /// Start a root shell instead of one as your user
+    root: bool,
+
+    #[structopt(flatten)]
+    session: SessionOpts,
+
+    #[structopt(short = "N", long = "nested")]
+    /// Allow running inside a container
+    nested: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct ExecOpts {
+    #[structopt(long = "root")]
+    /// Run as root rather than the toolbox user
+    root: bool,
+
+    #[structopt(long = "init-only")]
+    /// Only initialize the container
+    init_only: bool,
+
+    #[structopt(long = "init-command", raw(number_of_values = "1"))]
+    /// Shell command to run as the user when initializing the
+    /// container (repeatable)
+    init_commands: Vec<String>,
+
+    #[structopt(long = "shell", raw(number_of_values = "1"))]
+    /// Interactive shell to start; the first the image has is used,
+    /// falling back to bash and then sh
+    shells: Vec<String>,
+
+    #[structopt(
+        long = "install-shell",
+        default_value = "ask",
+        raw(possible_values = "&[\"ask\", \"always\", \"never\"]")
+    )]
+    /// Whether to install the shell if the image lacks it
+    install_shell: String,
+
+    #[structopt(long = "motd")]
+    /// Print the welcome message, unless already shown
+    motd: bool,
+
+    #[structopt(long = "workdir")]
+    /// Host directory the session was started from
+    workdir: Option<String>,
+
+    /// Command to run as the toolbox user
+    command: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct HostExecOpts {
+    #[structopt(raw(allow_hyphen_values = "true"))]
+    /// Command to run on the host
+    command: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct RmOpts {
+    #[structopt(short = "f", long = "force")]
+    /// Remove running containers, and containers not created by coretoolbox
+    force: bool,
+
+    #[structopt(short = "c", long = "container", raw(number_of_values = "1"))]
+    /// Name of a container to remove; may be repeated (default:
+    /// $CORETOOLBOX_CONTAINER)
+    containers: Vec<String>,
+
+    #[structopt(
+        short = "n",
+        long = "name",
+        raw(number_of_values = "1", hidden = "true")
+    )]
+    /// Old spelling of -c, kept so existing scripts work
+    legacy_names: Vec<String>,
+
+    /// Names of the containers to remove (default: coretoolbox-$USER-RELEASE)
+    names: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct StopOpts {
+    #[structopt(short = "a", long = "all")]
+    /// Stop all running toolbox containers
+    all: bool,
+
+    #[structopt(
+        short = "t",
+        long = "time",
+        default_value = "10",
+        env = "CORETOOLBOX_STOP_TIME"
+    )]
+    /// Seconds to wait after SIGTERM before sending SIGKILL
+    time: u32,
+
+    #[structopt(short = "c", long = "container", raw(number_of_values = "1"))]
+    /// Name of a container to stop; may be repeated (default:
+    /// $CORETOOLBOX_CONTAINER)
+    containers: Vec<String>,
+
+    /// Names of the containers to stop (default: coretoolbox-$USER-RELEASE)
+    names: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct UpgradeOpts {
+    #[structopt(flatten)]
+    container: ContainerOpts,
+
+    #[structopt(flatten)]
+    pullopts: PullOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct StatusOpts {
+    #[structopt(flatten)]
+    container: ContainerOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct ResetOpts {
+    #[structopt(short = "y", long = "yes")]
+    /// Don't ask for confirmation
+    yes: bool,
+
+    #[structopt(long = "keep-config")]
+    /// Don't remove the configuration
+    keep_config: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct RenameOpts {
+    /// Current name of the toolbox container
+    old: String,
+    /// New name for the toolbox container
+    new: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct LogsOpts {
+    #[structopt(short = "f", long = "follow")]
+    /// Keep printing new log output
+    follow: bool,
+
+    #[structopt(flatten)]
+    container: ContainerOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct PsOpts {
+    #[structopt(flatten)]
+    container: ContainerOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct StatsOpts {
+    #[structopt(long = "json")]
+    /// Print a single snapshot as JSON
+    json: bool,
+
+    #[structopt(short = "c", long = "container", raw(number_of_values = "1"))]
+    /// Name of a container; may be repeated (default:
+    /// $CORETOOLBOX_CONTAINER)
+    containers: Vec<String>,
+
+    /// Names of the containers (default: all running toolbox containers)
+    names: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct GcOpts {
+    #[structopt(long = "statefiles-only")]
+    /// Only remove stale statefiles, not exited containers
+    statefiles_only: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct ExportOpts {
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    /// Path of the archive to write
+    output: std::path::PathBuf,
+
+    #[structopt(long = "format", default_value = "oci-archive")]
+    /// Archive format: oci-archive or docker-archive
+    format: String,
+
+    #[structopt(flatten)]
+    container: ContainerOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct LoadOpts {
+    #[structopt(parse(from_os_str))]
+    /// Path of the image archive
+    input: std::path::PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct CommitOpts {
+    #[structopt(
+        short = "c",
+        long = "container",
+        env = "CORETOOLBOX_CONTAINER",
+        raw(default_value = "&DEFAULT_CONTAINER_NAME")
+    )]
+    /// Name of the toolbox container
+    container: String,
+
+    /// Name of the image to create
+    image: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct BuildOpts {
+    #[structopt(short = "f", long = "file", default_value = "Containerfile")]
+    /// Path to the Containerfile
+    file: String,
+
+    #[structopt(short = "t", long = "tag")]
+    /// Name of the image to build
+    tag: String,
+
+    #[structopt(long = "create")]
+    /// Create a toolbox container with this name from the new image
+    create: Option<String>,
+
+    #[structopt(default_value = ".")]
+    /// Build context directory
+    context: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct CpOpts {
+    /// Source: a host path, or NAME:PATH inside a toolbox
+    src: String,
+    /// Destination: a host path, or NAME:PATH inside a toolbox
+    dest: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct CheckpointOpts {
+    #[structopt(short = "e", long = "export")]
+    /// Also write the checkpoint to this archive, e.g. to survive a reboot
+    export: Option<String>,
+
+    #[structopt(flatten)]
+    container: ContainerOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct RestoreOpts {
+    #[structopt(short = "i", long = "import")]
+    /// Restore from a checkpoint archive written by `checkpoint --export`
+    import: Option<String>,
+
+    #[structopt(flatten)]
+    container: ContainerOpts,
+}
+
+#[derive(Debug, StructOpt)]
+struct GenerateSystemdOpts {
+    #[structopt(flatten)]
+    container: ContainerOpts,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum GenerateOpts {
+    /// Print a systemd user unit which starts the toolbox at login
+    Systemd(GenerateSystemdOpts),
+}
+
+#[derive(Debug, StructOpt)]
+struct CompletionOpts {
+    #[structopt(raw(possible_values = "&clap::Shell::variants()"))]
+    /// Shell to generate completions for
+    shell: clap::Shell,
+}
+
+#[derive(Debug, StructOpt)]
+struct ManOpts {
+    #[structopt(short = "o", long = "output-dir", parse(from_os_str))]
+    /// Write coretoolbox.1 and a page per subcommand into this directory
+    output_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct SearchOpts {
+    #[structopt(default_value = "toolbox")]
+    /// Term to search the configured registries for
+    term: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct InitImageOpts {
+    #[structopt(
+        short = "I",
+        long = "image",
+        env = "CORETOOLBOX_IMAGE",
+        raw(default_value = "default_image()")
+    )]
+    /// Base image to derive from
+    image: String,
+
+    #[structopt(short = "o", long = "output", default_value = "Containerfile")]
+    /// Path of the Containerfile to write
+    output: String,
+
+    #[structopt(short = "f", long = "force")]
+    /// Overwrite an existing file
+    force: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct PruneOpts {
+    #[structopt(long = "dry-run")]
+    /// Only print what would be removed
+    dry_run: bool,
+
+    #[structopt(long = "idle-days")]
+    /// Only remove containers that haven't been entered in this many days
+    idle_days: Option<u64>,
+}
+
+#[derive(Debug, StructOpt)]
+struct MountOpts {
+    #[structopt(
+        short = "c",
+        long = "container",
+        env = "CORETOOLBOX_CONTAINER",
+        raw(default_value = "&DEFAULT_CONTAINER_NAME")
+    )]
+    /// Name of the toolbox container
+    container: String,
+
+    /// Host path to expose
+    source: String,
+    /// Path inside the container (default: same as on the host)
+    dest: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct RmiOpts {
+    #[structopt(short = "f", long = "force")]
+    /// Remove images that are not toolbox images, or are in use
+    force: bool,
+
+    #[structopt(long = "unused")]
+    /// Remove all toolbox images not used by a toolbox container
+    unused: bool,
+
+    /// Images to remove
+    images: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "coretoolbox", about = "Toolbox")]
+struct Cli {
+    #[structopt(long = "offline", raw(global = "true"))]
+    /// Never contact a registry; only use images present locally
+    offline: bool,
+
+    #[structopt(subcommand)]
+    cmd: Opt,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Opt {
+    /// Create a persistent toolbox container
+    Create(CreateCmdOpts),
+    /// Create the toolbox if necessary, and run a command or shell in it
+    #[structopt(raw(setting = "clap::AppSettings::TrailingVarArg"))]
+    Run(RunOpts),
+    /// Enter an existing toolbox container
+    Enter(EnterOpts),
+    /// List toolbox containers and images
+    List,
+    /// Stop running toolbox containers
+    Stop(StopOpts),
+    /// Recreate a toolbox container using the latest version of its image
+    Upgrade(UpgradeOpts),
+    /// Show the state of a toolbox container
+    Status(StatusOpts),
+    /// Remove all containers, images and data created by coretoolbox
+    Reset(ResetOpts),
+    /// Rename a toolbox container
+    Rename(RenameOpts),
+    /// Show the log of a toolbox container, including entrypoint messages
+    Logs(LogsOpts),
+    /// List the processes running in a toolbox container
+    Ps(PsOpts),
+    /// Show resource usage of toolbox containers
+    Stats(StatsOpts),
+    /// Remove stale statefiles and exited toolbox containers
+    Gc(GcOpts),
+    /// Show disk usage of toolbox containers and images
+    Du,
+    /// Remove stopped toolbox containers and unused toolbox images and volumes
+    Prune(PruneOpts),
+    /// Save a toolbox container, including its changes, as an image archive
+    Export(ExportOpts),
+    /// Load a toolbox image from an archive
+    Load(LoadOpts),
+    /// Create a toolbox image from the changes made in a toolbox container
+    Commit(CommitOpts),
+    /// Build a toolbox image from a Containerfile
+    Build(BuildOpts),
+    /// Write a template Containerfile for a custom toolbox image
+    InitImage(InitImageOpts),
+    /// Copy files between the host and a toolbox container
+    Cp(CpOpts),
+    /// Add a bind mount to a toolbox container, keeping its changes
+    Mount(MountOpts),
+    /// Checkpoint a running toolbox container to disk using CRIU
+    Checkpoint(CheckpointOpts),
+    /// Restore a checkpointed toolbox container
+    Restore(RestoreOpts),
+    /// Check the host for common configuration problems
+    Doctor,
+    /// Generate files for integrating toolboxes with the host
+    Generate(GenerateOpts),
+    /// Print shell completions
+    Completion(CompletionOpts),
+    /// Generate man pages
+    Man(ManOpts),
+    /// Search registries for toolbox images
+    Search(SearchOpts),
+    /// Check whether toolbox containers use outdated images; exits
+    /// with status 100 if any do
+    CheckUpdate,
+    /// Delete toolbox containers
+    Rm(RmOpts),
+    /// Delete toolbox images
+    Rmi(RmiOpts),
+    /// From inside a toolbox, run a command on the host
+    #[structopt(raw(setting = "clap::AppSettings::TrailingVarArg"))]
+    HostExec(HostExecOpts),
+    /// Internal implementation detail; do not use
+    RunPid1,
+    /// Internal implementation detail; do not use
+    Exec(ExecOpts),
+}
+
+impl Opt {
+    /// Whether this has to run on the host, where podman and our
+    /// configuration are, rather than in a toolbox.
+    fn runs_on_host(&self) -> bool {
+        match self {
+            Opt::Run(ref opts) => !opts.nested,
+            Opt::Enter(ref opts) => !opts.nested,
+            Opt::Exec(_)
+            | Opt::HostExec(_)
+            | Opt::RunPid1
+            | Opt::InitImage(_)
+            | Opt::Completion(_)
+            | Opt::Man(_) => false,
+            _ => true,
+        }
+    }
+}
+
+fn cmd_podman() -> Command {
+    if let Some(podman) = std::env::var_os("podman") {
+        Command::new(podman)
+    } else {
+        Command::new("podman")
+    }
+}
+
+/// Returns true if the host is OSTree based
+fn is_ostree_based_host() -> bool {
+    std::path::Path::new("/run/ostree-booted").exists()
+}
+
+#[allow(dead_code)]
+enum InspectType {
+    Container,
+    Image,
+}
+
+/// Returns true if an image or container is in the podman
+/// storage.
+fn podman_has(t: InspectType, name: &str) -> Fallible<bool> {
+    let typearg = match t {
+        InspectType::Container => "container",
+        InspectType::Image => "image",
+    };
+    Ok(cmd_podman()
+        .args(["inspect", "--type", typearg, name])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?
+        .success())
+}
+
+/// Query a container or image with a `podman inspect` Go template.
+fn podman_inspect(t: InspectType, name: &str, format: &str) -> Fallible<String> {
+    let typearg = match t {
+        InspectType::Container => "container",
+        InspectType::Image => "image",
+    };
+    let out = cmd_podman()
+        .args(["inspect", "--type", typearg, "--format", format, name])
+        .run_get_output()?;
+    Ok(out.trim().to_string())
+}
+
+/// Look up a label on a container; returns an empty string if unset.
+fn container_label(name: &str, label: &str) -> Fallible<String> {
+    let format = format!("{{{{index .Config.Labels \"{}\"}}}}", label);
+    let v = podman_inspect(InspectType::Container, name, &format)?;
+    Ok(if v == "<no value>" { String::new() } else { v })
+}
+
+/// The profile a toolbox container was created with.
+fn container_profile(name: &str) -> Fallible<Option<String>> {
+    let v = container_label(name, "com.coreos.toolbox.profile")?;
+    Ok(if v.is_empty() { None } else { Some(v) })
+}
+
+/// Look up a label holding a JSON list, such as the extra podman
+/// arguments a container was created with.
+fn container_list_label(name: &str, label: &str) -> Fallible<Vec<String>> {
+    let v = container_label(name, label)?;
+    if v.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(&v)?)
+}
+
+/// The podman volume a toolbox container's isolated home is in.
+fn container_home_volume(name: &str) -> Fallible<Option<String>> {
+    let v = container_label(name, "com.coreos.toolbox.home-volume")?;
+    Ok(if v.is_empty() { None } else { Some(v) })
+}
+
+/// The additional volumes a toolbox container was created with.
+fn container_volumes(name: &str) -> Fallible<Vec<String>> {
+    container_list_label(name, "com.coreos.toolbox.volumes")
+}
+
+/// Returns true if the container was created by coretoolbox.
+fn is_toolbox_container(name: &str) -> Fallible<bool> {
+    Ok(container_label(name, "com.coreos.toolbox")? == "true")
+}
+
+/// Fail unless `name` is an existing container we created.
+fn require_toolbox_container(name: &str) -> Fallible<()> {
+    if !podman_has(InspectType::Container, name)? {
+        bail!("No such container: {}", name);
+    }
+    if !is_toolbox_container(name)? {
+        bail!("Container {} was not created by coretoolbox", name);
+    }
+    Ok(())
+}
+
+/// Returns true if the container is currently running.
+fn is_container_running(name: &str) -> Fallible<bool> {
+    Ok(podman_inspect(InspectType::Container, name, "{{.State.Running}}")? == "true")
+}
+
+/// Returns true if the image carries one of the toolbox labels.
+fn is_toolbox_image(name: &str) -> Fallible<bool> {
+    for label in TOOLBOX_IMAGE_LABELS {
+        let format = format!("{{{{index .Labels \"{}\"}}}}", label);
+        if podman_inspect(InspectType::Image, name, &format)? == "true" {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether the image has a CMD or ENTRYPOINT.
+fn image_has_command(name: &str) -> Fallible<bool> {
+    let format = "{{len .Config.Cmd}} {{len .Config.Entrypoint}}";
+    let lens = podman_inspect(InspectType::Image, name, format)?;
+    Ok(lens.split_whitespace().any(|n| n != "0"))
+}
+
+/// Whether we're in offline mode, from `--offline` or the
+/// configuration.
+fn offline() -> bool {
+    OFFLINE.load(std::sync::atomic::Ordering::Relaxed)
+        || CONFIG.as_ref().ok().and_then(|c| c.offline).unwrap_or(false)
+}
+
+/// Fail, rather than hang, if we would have to contact a registry
+/// in offline mode.
+fn check_online(what: &str) -> Fallible<()> {
+    if offline() {
+        bail!("Can't {} in offline mode", what);
+    }
+    Ok(())
+}
+
+/// Load archives from the configured directory until `image` is
+/// present.  Returns false if it isn't in any of them.
+fn load_image_from_archives(image: &str) -> Fallible<bool> {
+    let dir = match config()?.image_archives {
+        Some(ref d) => d,
+        None => return Ok(false),
+    };
+    let mut archives: Vec<std::path::PathBuf> = std::fs::read_dir(dir)
+        .with_context(|e| format!("Reading {}: {}", dir, e))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    archives.sort();
+    for archive in archives {
+        if !archive.is_file() {
+            continue;
+        }
+        println!("Loading {}", archive.display());
+        cmd_podman()
+            .arg("load")
+            .arg("--input")
+            .arg(&archive)
+            .stdout(Stdio::null())
+            .run()?;
+        if podman_has(InspectType::Image, image)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Run `podman pull` once, passing its output through.  If it
+/// fails, return what it printed on stderr.
+fn podman_pull_once(image: &str, opts: &PullOpts) -> Fallible<Result<(), String>> {
+    let mut podman = cmd_podman();
+    podman.arg("pull");
+    if let Some(authfile) = opts.authfile() {
+        podman.args(["--authfile", authfile]);
+    }
+    if let Some(policy) = opts.signature_policy() {
+        podman.args(["--signature-policy", policy]);
+    }
+    if let Some(arch) = opts.podman_arch() {
+        podman.args(["--arch", arch]);
+    }
+    let mut child = podman.arg(image).stderr(Stdio::piped()).spawn()?;
+    let mut errors = String::new();
+    for line in std::io::BufReader::new(child.stderr.take().unwrap()).lines() {
+        let line = line?;
+        eprintln!("{}", line);
+        errors.push_str(&line);
+        errors.push('\n');
+    }
+    Ok(if child.wait()?.success() { Ok(()) } else { Err(errors) })
+}
+
+/// Whether a pull failed because the registry wants credentials.
+fn pull_error_is_auth(errors: &str) -> bool {
+    let errors = errors.to_lowercase();
+    ["unauthorized", "authentication required"]
+        .iter()
+        .any(|e| errors.contains(e))
+}
+
+/// Whether a pull failure is worth retrying, as opposed to e.g. a
+/// missing image or a refused login.
+fn pull_error_is_transient(errors: &str) -> bool {
+    let errors = errors.to_lowercase();
+    ![
+        "unauthorized",
+        "authentication required",
+        "denied",
+        "manifest unknown",
+        "not found",
+        "invalid reference",
+        // By the signature policy
+        "rejected",
+    ]
+    .iter()
+    .any(|e| errors.contains(e))
+}
+
+/// Pull `image`, retrying transient failures with backoff.
+fn podman_pull_with_retries(image: &str, opts: &PullOpts) -> Fallible<Result<(), String>> {
+    let mut delay = std::time::Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        let errors = match podman_pull_once(image, opts)? {
+            Ok(()) => return Ok(Ok(())),
+            Err(errors) => errors,
+        };
+        if attempt == PULL_ATTEMPTS || !pull_error_is_transient(&errors) {
+            return Ok(Err(errors));
+        }
+        eprintln!("Pulling {} failed, retrying in {}s", image, delay.as_secs());
+        std::thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Split an image reference into its registry, if it names one,
+/// and the rest.
+fn split_registry(image: &str) -> Option<(&str, &str)> {
+    match image.split_once('/') {
+        Some((r, rest)) if r.contains('.') || r.contains(':') || r == "localhost" => Some((r, rest)),
+        _ => None,
+    }
+}
+
+/// The same image on each configured mirror of its registry.
+fn mirror_references(image: &str) -> Fallible<Vec<String>> {
+    let (registry, rest) = match split_registry(image) {
+        Some(r) => r,
+        None => return Ok(Vec::new()),
+    };
+    Ok(config()?
+        .registry_mirrors
+        .get(registry)
+        .map(|mirrors| {
+            mirrors
+                .iter()
+                .map(|m| format!("{}/{}", m.trim_end_matches('/'), rest))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Pull an image, falling back to the configured mirrors for its
+/// registry.  An image from a mirror is tagged with the original
+/// name, so it's found as usual afterwards.
+fn pull_image(image: &str, opts: &PullOpts) -> Fallible<()> {
+    check_online(&format!("pull {}", image))?;
+    let mut last_errors = String::new();
+    for source in std::iter::once(image.to_string()).chain(mirror_references(image)?) {
+        if source != image {
+            eprintln!("Trying mirror {}", source);
+        }
+        match podman_pull_with_retries(&source, opts)? {
+            Ok(()) => {
+                if source != image {
+                    cmd_podman().args(["tag", source.as_str(), image]).run()?;
+                }
+                return Ok(());
+            }
+            Err(errors) => last_errors = errors,
+        }
+    }
+    if pull_error_is_auth(&last_errors) {
+        let login = match split_registry(image) {
+            Some((registry, _)) => format!("podman login {}", registry),
+            None => "podman login".to_string(),
+        };
+        bail!(
+            "Pulling {} failed: the registry requires authentication; \
+             log in with `{}`, or pass --authfile",
+            image,
+            login
+        );
+    }
+    bail!("Pulling {} failed: {}", image, last_errors.trim())
+}
+
+/// Pull a container image if not present
+fn ensure_image(name: &str, policy: PullPolicy, opts: &PullOpts) -> Fallible<()> {
+    // Signatures are only checked when pulling, so one that's
+    // already present has to be pulled again to be verified.
+    let policy = match policy {
+        PullPolicy::Missing if opts.signature_policy().is_some() => PullPolicy::Always,
+        // The local image may be for another architecture.
+        PullPolicy::Missing => match opts.podman_arch() {
+            Some(arch)
+                if podman_has(InspectType::Image, name)?
+                    && podman_inspect(InspectType::Image, name, "{{.Architecture}}")? != arch =>
+            {
+                PullPolicy::Always
+            }
+            _ => policy,
+        },
+        p => p,
+    };
+    // Signatures are only checked when pulling, so a local or loaded
+    // image can't be verified.
+    if opts.signature_policy().is_some() {
+        if offline() {
+            bail!("Can't verify the signature of {} in offline mode", name);
+        }
+        if policy == PullPolicy::Never {
+            bail!(
+                "Can't verify the signature of {} with the pull policy never",
+                name
+            );
+        }
+    }
+    if offline() {
+        if !podman_has(InspectType::Image, name)? && !load_image_from_archives(name)? {
+            bail!(
+                "Image {} is not present locally, and can't be pulled in offline mode",
+                name
+            );
+        }
+        return Ok(());
+    }
+    match policy {
+        PullPolicy::Always => pull_image(name, opts)?,
+        PullPolicy::Missing if !podman_has(InspectType::Image, name)? => pull_image(name, opts)?,
+        PullPolicy::Never if !podman_has(InspectType::Image, name)? => bail!(
+            "Image {} is not present locally, and the pull policy is never",
+            name
+        ),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Look up an executable in $PATH.
+fn find_in_path(name: &str) -> Option<std::path::PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|d| d.join(name))
+        .find(|p| p.is_file())
+}
+
+/// Parse group(5) into (name, gid) pairs.
+fn read_group_file(path: &str) -> Fallible<Vec<(String, u32)>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split(':');
+            let name = fields.next()?;
+            let gid = fields.nth(1)?.parse().ok()?;
+            Some((name.to_string(), gid))
+        })
+        .collect())
+}
+
+/// Our supplementary groups, other than the primary one, that have
+/// names in the host's /etc/group.
+fn host_supplementary_groups() -> Fallible<Vec<(String, u32)>> {
+    let primary = nix::unistd::getgid();
+    let known = read_group_file("/etc/group")?;
+    Ok(nix::unistd::getgroups()?
+        .into_iter()
+        .filter(|g| *g != primary)
+        .filter_map(|g| {
+            let gid: u32 = g.as_raw();
+            known.iter().find(|(_, id)| *id == gid).cloned()
+        })
+        .collect())
+}
+
+/// The installed podman's version, from `podman --version`.
+fn podman_version() -> Fallible<(u32, u32, u32)> {
+    parse_podman_version(&cmd_podman().arg("--version").run_get_output()?)
+}
+
+/// Parse the output of `podman --version`, e.g. "podman version 1.4.4".
+fn parse_podman_version(out: &str) -> Fallible<(u32, u32, u32)> {
+    let version = out
+        .split_whitespace()
+        .last()
+        .ok_or_else(|| failure::format_err!("Unexpected podman --version output: {}", out))?;
+    let mut parts = version.split('.').map(|p| {
+        // Ignore suffixes as in 1.6.0-rc1 or 4.9.4-dev
+        let digits: String = p.chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse::<u32>().unwrap_or(0)
+    });
+    let mut next = || parts.next().unwrap_or(0);
+    Ok((next(), next(), next()))
+}
+
+/// The number of subordinate ids allocated to us in `path`, which
+/// must be enough to map `id` into our user namespace (which has one
+/// more: our own id, as 0).
+fn subid_count(path: &str, username: &str, uid: u32, id: u32) -> Fallible<u32> {
+    match read_subid_range(path, username, uid)? {
+        Some((_, count)) if count >= id => Ok(count),
+        Some((_, count)) => bail!(
+            "Only {} subordinate ids are allocated to {} in {}, at least {} are needed; see `coretoolbox doctor`",
+            count,
+            username,
+            path,
+            id
+        ),
+        None => bail!(
+            "No subordinate ids allocated to {} in {}; see `coretoolbox doctor`",
+            username,
+            path
+        ),
+    }
+}
+
+/// Map `id` to itself, shifting the ids below it up by one to make
+/// room for the root of our user namespace, which is `id` on the host.
+/// `count` is the number of subordinate ids, so the namespace holds
+/// ids 0 to `count`.
+fn id_mappings(flag: &str, id: u32, count: u32) -> Vec<String> {
+    let mut maps = vec![format!("{}={}:0:1", flag, id), format!("{}=0:1:{}", flag, id)];
+    if count > id {
+        maps.push(format!("{}={}:{}:{}", flag, id + 1, id + 1, count - id));
+    }
+    maps
+}
+
+/// Find the subordinate id range (start, count) allocated to a user
+/// in /etc/subuid or /etc/subgid; entries may use the name or the id.
+fn read_subid_range(path: &str, username: &str, id: u32) -> Fallible<Option<(u32, u32)>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let idstr = id.to_string();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if fields.len() != 3 || (fields[0] != username && fields[0] != idstr) {
+            continue;
+        }
+        let start = fields[1].parse()?;
+        let count = fields[2].parse()?;
+        return Ok(Some((start, count)));
+    }
+    Ok(None)
+}
+
+/// Package names are passed to the image's package manager as is, so
+/// make sure they can't be taken for an option, a URL or a local
+/// package file.
+fn check_package_name(name: &str) -> Fallible<()> {
+    if name.is_empty() || name.starts_with('-') || name.contains('/') {
+        bail!("Invalid package name: {}", name);
+    }
+    Ok(())
+}
+
+/// Quote a string for use in a POSIX shell command line.
+fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Render a command as a shell command line.
+fn format_command(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|a| shell_quote(&a.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse an extant environment variable as UTF-8
+fn getenv_required_utf8(n: &str) -> Fallible<String> {
+    if let Some(v) = std::env::var_os(n) {
+        Ok(v.to_str()
+            .ok_or_else(|| failure::format_err!("{} is invalid UTF-8", n))?
+            .to_string())
+    } else {
+        bail!("{} is unset", n)
+    }
+}
+
+/// Expand a leading `~` and `$VAR` or `${VAR}` references, as a
+/// shell would, in a volume from the configuration.
+fn expand_volume(v: &str) -> Fallible<String> {
+    let mut out = String::new();
+    let mut rest = v;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~:") {
+        out.push_str(&getenv_required_utf8("HOME")?);
+        rest = &rest[1..];
+    }
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, len) = if rest.starts_with('{') {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| failure::format_err!("Unterminated ${{ in volume: {}", v))?;
+            (&rest[1..end], end + 1)
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+        if name.is_empty() {
+            bail!("Invalid variable reference in volume: {}", v);
+        }
+        out.push_str(
+            &getenv_required_utf8(name)
+                .map_err(|e| failure::format_err!("Expanding volume {}: {}", v, e))?,
+        );
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EntrypointState {
+    /// The container's name and image, for /run/.toolboxenv
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    image: Option<String>,
+    username: String,
+    uid: u32,
+    /// Primary gid; older containers used the uid
+    #[serde(default)]
+    gid: Option<u32>,
+    home: String,
+    /// The home directory is a volume, not the host's
+    #[serde(default)]
+    isolated_home: bool,
+    ostree_based_host: bool,
+    /// Packages to install on first start
+    #[serde(default)]
+    packages: Vec<String>,
+    /// Contents of the user's post-create hook, run once; only from
+    /// EntrypointSecrets, or the state of older containers
+    #[serde(default, skip_serializing)]
+    post_create: Option<String>,
+    /// Copy the proxy settings from the host's dnf.conf
+    #[serde(default)]
+    dnf_proxy: bool,
+    /// Devices to forward in addition to FORWARDED_DEVICES
+    #[serde(default)]
+    devices: Vec<String>,
+    /// The user's supplementary groups on the host, as (name, gid),
+    /// to recreate in the container
+    #[serde(default)]
+    groups: Vec<(String, u32)>,
+    /// Podman keeps the supplementary groups instead, so sessions
+    /// mustn't reset them
+    #[serde(default)]
+    keep_groups: bool,
+    /// Add a NOPASSWD sudo rule for the user; older containers did
+    #[serde(default)]
+    passwordless_sudo: Option<bool>,
+    /// Use the host's machine-id; older containers didn't
+    #[serde(default)]
+    share_machine_id: bool,
+    /// CONTAINER_HOST for sessions, for the host's podman service
+    #[serde(default)]
+    container_host: Option<String>,
+    /// Commands to run on the host; older containers get HOST_COMMANDS
+    #[serde(default)]
+    host_commands: Option<Vec<String>>,
+    /// Password field for the user's /etc/shadow entry, if set; as
+    /// for post_create
+    #[serde(default, skip_serializing)]
+    password_hash: Option<String>,
+}
+
+/// The parts of the entrypoint's state that shouldn't be in the
+/// container's configuration, where `podman inspect` shows them and
+/// `podman commit` copies them into images.  They're passed in a file
+/// in XDG_RUNTIME_DIR only we can read, which the entrypoint removes
+/// on first start.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct EntrypointSecrets {
+    #[serde(default)]
+    post_create: Option<String>,
+    #[serde(default)]
+    password_hash: Option<String>,
+}
+
+impl EntrypointSecrets {
+    fn load() -> Fallible<EntrypointSecrets> {
+        Ok(EntrypointSecrets {
+            post_create: read_post_create_hook()?,
+            password_hash: password_hash()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.post_create.is_none() && self.password_hash.is_none()
+    }
+}
+
+/// Name of the file in XDG_RUNTIME_DIR passing a container's
+/// EntrypointSecrets.
+fn secrets_file_name(name: &str) -> String {
+    format!("coreos-toolbox-{}.secrets", name)
+}
+
+/// Write the file passing `secrets` to the container `name`.
+fn write_secrets_file(name: &str, secrets: &EntrypointSecrets) -> Fallible<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let runtime_dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(d) => std::path::PathBuf::from(d),
+        None => bail!("XDG_RUNTIME_DIR must be set to pass the password or post-create hook"),
+    };
+    let path = runtime_dir.join(secrets_file_name(name));
+    let f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|e| format!("Writing {}: {}", path.display(), e))?;
+    let mut w = std::io::BufWriter::new(f);
+    serde_json::to_writer(&mut w, secrets)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// The shadow(5) password field to set for the user, from the
+/// configuration.
+fn password_hash() -> Fallible<Option<String>> {
+    let config = config()?;
+    if config.lock_password.unwrap_or(false) {
+        return Ok(Some("!".to_string()));
+    }
+    match config.password_hash {
+        Some(ref h) if !h.starts_with('$') || h.contains(':') || h.contains('\n') => {
+            bail!("password-hash must be a crypt(3) hash, as from `openssl passwd -6`")
+        }
+        ref h => Ok(h.clone()),
+    }
+}
+
+/// The user's post-create hook, run in each new toolbox.  This is
+/// deliberately not a configuration setting, so that a project's
+/// configuration can't run code.
+fn read_post_create_hook() -> Fallible<Option<String>> {
+    let path = APPDIRS.config_dir().join("post-create.sh");
+    match std::fs::read_to_string(&path) {
+        Ok(s) => Ok(Some(s)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(failure::format_err!("Reading {}: {}", path.display(), e)),
+    }
+}
+
+/// Parse a file of `KEY=VALUE` lines; blank lines and lines
+/// starting with `#` are ignored.
+fn read_env_file(path: &Path) -> Fallible<Vec<(String, String)>> {
+    let buf = std::fs::read_to_string(path)
+        .with_context(|e| format!("Reading {}: {}", path.display(), e))?;
+    let mut r = Vec::new();
+    for (i, line) in buf.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let kv = parse_env_assignment(line)
+            .map_err(|e| failure::format_err!("{}:{}: {}", path.display(), i + 1, e))?;
+        r.push(kv);
+    }
+    Ok(r)
+}
+
+/// Where a container's environment file is kept.
+fn container_env_file(name: &str) -> std::path::PathBuf {
+    container_data_dir(name).join("env")
+}
+
+/// Forward the host environment variables in PRESERVED_ENV, plus
+/// those added by the configuration and `env`, less those unset by
+/// either; then set the variables from the configuration, `file_env`
+/// and `env`, in that order.
+fn append_env(c: &mut Command, session: &SessionOpts, file_env: &[(String, String)]) -> Fallible<()> {
+    let config = config()?;
+    let unset: Vec<&str> = config
+        .unset_env
+        .iter()
+        .chain(&session.unset_env)
+        .map(|s| s.as_str())
+        .collect();
+    let names = PRESERVED_ENV
+        .iter()
+        .copied()
+        .chain(config.preserve_env.iter().map(|s| s.as_str()))
+        .chain(session.preserve_env.iter().map(|s| s.as_str()))
+        .filter(|n| !unset.contains(n));
+    let mut seen = Vec::new();
+    for n in names {
+        if seen.contains(&n) {
+            continue;
+        }
+        seen.push(n);
+        let v = match std::env::var_os(n) {
+            Some(v) => v,
+            None => continue,
+        };
+        let v = v
+            .to_str()
+            .ok_or_else(|| failure::format_err!("{} contains invalid UTF-8", n))?;
+        c.arg(format!("--env={}={}", n, v));
+    }
+    let explicit = config.env.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+    let explicit = explicit
+        .chain(file_env.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .chain(session.env.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    for (k, v) in explicit {
+        c.arg(format!("--env={}={}", k, v));
+        if !seen.contains(&k) {
+            seen.push(k);
+        }
+    }
+    // su in the entrypoint only lets through PRESERVED_ENV, and
+    // what we tell it about here.
+    let extra: Vec<&str> = seen.into_iter().filter(|n| !PRESERVED_ENV.contains(n)).collect();
+    if !extra.is_empty() {
+        c.arg(format!("--env=TOOLBOX_PRESERVED_ENV={}", extra.join(",")));
+    }
+    // Values the container was created with would otherwise still
+    // show through.
+    if !unset.is_empty() {
+        c.arg(format!("--env=TOOLBOX_UNSET_ENV={}", unset.join(",")));
+    }
+    Ok(())
+}
+
+/// Built-in image aliases: `fNN` for a Fedora release, `rawhide`,
+/// and `ubi8`/`ubi9` (`ubi` being the latest).  Fedora moved its
+/// toolbox images out of per-release repositories with 31.
+fn builtin_image_alias(alias: &str) -> Option<String> {
+    if let Some(release) = alias.strip_prefix('f') {
+        let n: u32 = release.parse().ok()?;
+        return Some(if n <= 30 {
+            format!("registry.fedoraproject.org/f{0}/fedora-toolbox:{0}", n)
+        } else {
+            format!("registry.fedoraproject.org/fedora-toolbox:{}", n)
+        });
+    }
+    let image = match alias {
+        "rawhide" => "registry.fedoraproject.org/fedora-toolbox:rawhide",
+        "ubi8" => "registry.access.redhat.com/ubi8/toolbox",
+        "ubi9" | "ubi" => "registry.access.redhat.com/ubi9/toolbox",
+        _ => return None,
+    };
+    Some(image.to_string())
+}
+
+/// Resolve an alias from the configuration or the built-in ones;
+/// anything else is taken to be an image name already.
+fn resolve_image(image: &str) -> String {
+    let configured = CONFIG.as_ref().ok().and_then(|c| c.image_aliases.get(image));
+    match configured {
+        Some(image) => image.clone(),
+        None => builtin_image_alias(image).unwrap_or_else(|| image.to_string()),
+    }
+}
+
+/// Parse the host's os-release(5).  From inside a toolbox, the host's
+/// copy is under /host.
+fn host_os_release() -> Fallible<std::collections::HashMap<String, String>> {
+    let path = if in_container() && Path::new("/host/etc/os-release").exists() {
+        "/host/etc/os-release"
+    } else {
+        "/etc/os-release"
+    };
+    read_os_release(path)
+}
+
+fn read_os_release(path: &str) -> Fallible<std::collections::HashMap<String, String>> {
+    let buf = std::fs::read_to_string(path)?;
+    Ok(buf
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().trim_matches('"').to_string()))
+        .collect())
+}
+
+/// A human-readable name for the host's release, e.g. "Fedora 30
+/// (Workstation Edition)".
+fn host_os_description() -> Option<String> {
+    let os_release = host_os_release().ok()?;
+    if let Some(pretty) = os_release.get("PRETTY_NAME") {
+        return Some(pretty.clone());
+    }
+    let id = os_release.get("ID")?;
+    Some(match os_release.get("VERSION_ID") {
+        Some(v) => format!("{} {}", id, v),
+        None => id.clone(),
+    })
+}
+
+/// The toolbox image for the host's release: the same Fedora release,
+/// or UBI for RHEL.
+fn host_image() -> Option<String> {
+    let os_release = host_os_release().ok()?;
+    let version = os_release.get("VERSION_ID")?;
+    let major = version.split('.').next()?;
+    match os_release.get("ID")?.as_str() {
+        "fedora" => builtin_image_alias(&format!("f{}", major)),
+        "rhel" => builtin_image_alias(&format!("ubi{}", major)),
+        _ => None,
+    }
+}
+
+/// The configured default image, or the one for the host's release,
+/// or our own.
+fn default_image() -> &'static str {
+    CONFIG
+        .as_ref()
+        .ok()
+        .and_then(|c| c.image.as_deref())
+        .or_else(|| HOST_IMAGE.as_deref())
+        .unwrap_or(DEFAULT_IMAGE)
+}
+
+/// The default container name for an image: `coretoolbox-$USER`,
+/// followed by the image's release (its tag) unless that's `latest`.
+/// Including the user keeps root and other users on the same host
+/// from colliding over a shared name.
+fn default_container_name(image: &str) -> String {
+    let user = std::env::var("USER")
+        .unwrap_or_else(|_| u32::from(nix::unistd::getuid()).to_string());
+    let name = image.rsplit('/').next().unwrap_or(image);
+    match name.split_once(':') {
+        Some((_, tag)) if !tag.is_empty() && tag != "latest" => {
+            format!("coretoolbox-{}-{}", user, tag)
+        }
+        _ => format!("coretoolbox-{}", user),
+    }
+}
+
+/// Where podman in the toolbox finds the host's podman service, if
+/// asked for: the user's socket in the runtime dir, which the
+/// entrypoint mounts at the same path, or for root the system one.
+fn podman_socket_url(opts: &CreateOpts) -> Fallible<Option<String>> {
+    if !opts.podman_socket && !config()?.podman_socket.unwrap_or(false) {
+        return Ok(None);
+    }
+    let (host_path, path) = if nix::unistd::getuid().is_root() {
+        let p = "/run/podman/podman.sock";
+        (p.to_string(), format!("/host{}", p))
+    } else {
+        let p = format!("{}/podman/podman.sock", getenv_required_utf8("XDG_RUNTIME_DIR")?);
+        (p.clone(), p)
+    };
+    if !Path::new(&host_path).exists() {
+        let unit = if nix::unistd::getuid().is_root() { "" } else { "--user " };
+        eprintln!(
+            "warning: {} doesn't exist; start the service with `systemctl {}enable --now podman.socket`",
+            host_path, unit
+        );
+    }
+    Ok(Some(format!("unix://{}", path)))
+}
+
+/// An image committed from a toolbox container, to recreate it from.
+/// The container keeps recording the image it was originally created
+/// from, and its digest, so that upgrades and update checks still
+/// refer to the upstream image.
+struct Snapshot {
+    image: String,
+    digest: String,
+}
+
+/// Build the `podman create` invocation for a toolbox container,
+/// including the state to pass to its entrypoint.  Additional
+/// `volumes`, and those given with `--volume`, are recorded in a
+/// label so they survive recreation.
+fn create_command(
+    opts: &CreateOpts,
+    volumes: &[String],
+    snapshot: Option<&Snapshot>,
+) -> Fallible<Command> {
+    let volumes: Vec<String> = volumes.iter().chain(&opts.volumes).cloned().collect();
+    // exec ourself as the entrypoint.  In the future this
+    // would be better with podman fd passing.
+    let self_bin = std::fs::read_link("/proc/self/exe")?;
+    let self_bin = self_bin
+        .as_path()
+        .to_str()
+        .ok_or_else(|| failure::err_msg("non-UTF8 self"))?;
+
+    let mut podman = cmd_podman();
+    podman.args([
+        "create",
+        "--interactive",
+        "--tty",
+        "--hostname=toolbox",
+        "--network=host",
+        "--privileged",
+        "--security-opt=label=disable",
+        "--label=com.coreos.toolbox=true",
+        "--tmpfs=/run:rw"
+    ]);
+    let name = opts.container_name();
+    podman.arg(format!("--name={}", name));
+    // Record how the container was created so later invocations
+    // can find their way back to it.
+    podman.arg(format!("--label=com.coreos.toolbox.name={}", name));
+    let image = opts.image();
+    podman.arg(format!("--label=com.coreos.toolbox.image={}", image));
+    // And with what, so status and upgrade have something to go on
+    // even after the image or the host has moved on.
+    podman.arg(format!(
+        "--label=com.coreos.toolbox.version={}",
+        env!("CARGO_PKG_VERSION")
+    ));
+    if let Some(snapshot) = snapshot {
+        if !snapshot.digest.is_empty() {
+            podman.arg(format!(
+                "--label=com.coreos.toolbox.image-digest={}",
+                snapshot.digest
+            ));
+        }
+    } else if podman_has(InspectType::Image, &image)? {
+        // With --show-command the image may not have been pulled yet.
+        let digest = podman_inspect(InspectType::Image, &image, "{{.Digest}}")?;
+        if !digest.is_empty() {
+            podman.arg(format!("--label=com.coreos.toolbox.image-digest={}", digest));
+        }
+    }
+    if let Some(host_os) = host_os_description() {
+        podman.arg(format!("--label=com.coreos.toolbox.host-os={}", host_os));
+    }
+    if !volumes.is_empty() {
+        podman.arg(format!(
+            "--label=com.coreos.toolbox.volumes={}",
+            serde_json::to_string(&volumes)?
+        ));
+    }
+    podman.arg(format!("--volume={}:/usr/bin/toolbox:ro", self_bin));
+    let real_uid: u32 = nix::unistd::getuid().into();
+    let real_gid: u32 = nix::unistd::getgid().into();
+    if let Some(arch) = opts.pullopts.podman_arch() {
+        podman.arg(format!("--arch={}", arch));
+        podman.arg(format!("--label=com.coreos.toolbox.arch={}", arch));
+    }
+    let version = podman_version().ok();
+    let keep_id_version = match opts.userns_size {
+        Some(_) => KEEP_ID_SIZE_PODMAN_VERSION,
+        None => KEEP_ID_PODMAN_VERSION,
+    };
+    let keep_id = real_uid != 0 && version.is_some_and(|v| v >= keep_id_version);
+    let uidmap = match (real_uid, keep_id) {
+        (0, _) => "none",
+        (_, true) => "keep-id",
+        (_, false) => "keep-uid",
+    };
+    podman.arg(format!("--label=com.coreos.toolbox.uidmap={}", uidmap));
+    if let Some(size) = opts.userns_size {
+        if real_uid != 0 && size <= real_uid.max(real_gid) {
+            bail!("--userns-size must be more than {} to map our own ids", real_uid.max(real_gid));
+        }
+        podman.arg(format!("--label=com.coreos.toolbox.userns-size={}", size));
+    }
+    // In true privileged mode we don't use userns
+    if keep_id {
+        // podman maps our ids to themselves and the rest of the
+        // namespace to our subordinate ids, as we do by hand below.
+        // It also makes us the default user, but the entrypoint
+        // needs to be root.
+        match opts.userns_size {
+            Some(size) => podman.arg(format!("--userns=keep-id:size={}", size)),
+            None => podman.arg("--userns=keep-id"),
+        };
+        podman.arg("--user=root");
+    } else if real_uid != 0 {
+        let username = getenv_required_utf8("USER")?;
+        // The namespace holds our own id as well as the subordinate ones.
+        let limit = |count: u32| opts.userns_size.map_or(count, |size| count.min(size - 1));
+        let count = subid_count("/etc/subuid", &username, real_uid, real_uid)?;
+        podman.args(id_mappings("--uidmap", real_uid, limit(count)));
+        // And the same for our primary group, so files we create on
+        // host mounts get the group they would on the host.
+        let count = subid_count("/etc/subgid", &username, real_uid, real_gid)?;
+        podman.args(id_mappings("--gidmap", real_gid, limit(count)));
+    }
+    // Our supplementary groups, e.g. video or dialout, aren't mapped
+    // into a rootless container, so have podman keep them where it
+    // can; they show up as nogroup inside, but device permissions
+    // work the same as on the host.  Otherwise the entrypoint
+    // recreates them by name and gid.
+    let keep_groups =
+        real_uid != 0 && version.is_some_and(|v| v >= KEEP_GROUPS_PODMAN_VERSION);
+    if keep_groups {
+        podman.arg("--group-add=keep-groups");
+    }
+
+    for p in &["/dev", "/usr", "/var", "/etc", "/run", "/tmp"] {
+        podman.arg(format!("--volume={}:/host{}:rslave", p, p));
+    }
+    if is_ostree_based_host() {
+        podman.arg("--volume=/sysroot:/host/sysroot:rslave");
+    } else {
+        for p in &["/media", "/mnt", "/home", "/srv"] {
+            podman.arg(format!("--volume={}:/host{}:rslave", p, p));
+        }
+    }
+    // Configured volumes aren't part of the label; they're added
+    // again whenever the container is recreated, as is the profile.
+    let profile = opts.profile()?;
+    let profile_volumes = profile.map(|p| p.volumes.as_slice()).unwrap_or_default();
+    for v in config()?.volumes.iter().chain(profile_volumes) {
+        podman.arg(format!("--volume={}", expand_volume(v)?));
+    }
+    // These came from the command line, already expanded by the shell.
+    for v in &volumes {
+        podman.arg(format!("--volume={}", v));
+    }
+    if let Some(ref profile) = opts.profile {
+        podman.arg(format!("--label=com.coreos.toolbox.profile={}", profile));
+    }
+    // The volume outlives the container, so the home directory
+    // survives upgrades.
+    let home = getenv_required_utf8("HOME")?;
+    let home_volume = opts.home_volume();
+    if let Some(ref v) = home_volume {
+        podman.arg(format!("--label=com.coreos.toolbox.home-volume={}", v));
+        podman.arg(format!("--volume={}:{}", v, home));
+    }
+    for d in &opts.devices {
+        if !d.starts_with("/dev/") || d.split('/').any(|c| c == "..") {
+            bail!("Invalid device {}: must be a path under /dev", d);
+        }
+    }
+    if !opts.devices.is_empty() {
+        podman.arg(format!(
+            "--label=com.coreos.toolbox.devices={}",
+            serde_json::to_string(&opts.devices)?
+        ));
+    }
+    let profile_env: Vec<(String, String)> = profile
+        .map(|p| p.env.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+        .unwrap_or_default();
+    append_env(&mut podman, &SessionOpts::default(), &profile_env)?;
+
+    let username = getenv_required_utf8("USER")?;
+    podman.arg(format!("--label=com.coreos.toolbox.user={}", username));
+    let mut packages = config()?.packages.clone();
+    if let Some(p) = profile {
+        packages.extend(p.packages.iter().cloned());
+    }
+    for p in &packages {
+        check_package_name(p)?;
+    }
+    let state = EntrypointState {
+        name: Some(name.clone()),
+        image: Some(image.clone()),
+        username,
+        uid: real_uid,
+        gid: Some(real_gid),
+        home,
+        isolated_home: home_volume.is_some(),
+        ostree_based_host: is_ostree_based_host(),
+        packages,
+        post_create: None,
+        dnf_proxy: config()?.dnf_proxy.unwrap_or(false),
+        devices: opts.devices.clone(),
+        groups: if keep_groups { Vec::new() } else { host_supplementary_groups()? },
+        keep_groups,
+        passwordless_sudo: Some(config()?.passwordless_sudo.unwrap_or(true)),
+        password_hash: None,
+        share_machine_id: config()?.share_machine_id.unwrap_or(true),
+        container_host: podman_socket_url(opts)?,
+        host_commands: Some(match config()?.host_commands {
+            Some(ref commands) => commands.clone(),
+            None => HOST_COMMANDS.iter().map(|c| c.to_string()).collect(),
+        }),
+    };
+    // In the container's configuration rather than a file on the
+    // host, so there's nothing to clean up or lose.
+    if state.container_host.is_some() {
+        podman.arg("--label=com.coreos.toolbox.podman-socket=true");
+    }
+    podman.arg(format!("--env=TOOLBOX_STATE={}", serde_json::to_string(&state)?));
+    if !EntrypointSecrets::load()?.is_empty() {
+        podman.arg(format!("--env=TOOLBOX_SECRETS={}", secrets_file_name(&name)));
+    }
+
+    // Last, so they can override what we set above.  Only those from
+    // the command line are recorded; the configured ones are added
+    // again on recreation, like configured volumes.
+    podman.args(&config()?.extra_podman_args);
+    if !opts.podman_args.is_empty() {
+        podman.arg(format!(
+            "--label=com.coreos.toolbox.podman-args={}",
+            serde_json::to_string(&opts.podman_args)?
+        ));
+        podman.args(&opts.podman_args);
+    }
+    podman.arg(snapshot.map_or(image.as_str(), |s| s.image.as_str()));
+    podman.args(["/usr/bin/toolbox", "run-pid1"]);
+    Ok(podman)
+}
+
+/// The `podman volume create` invocation for a home volume, if it
+/// doesn't exist yet.  It's labelled like our containers, so prune
+/// and reset find it.
+fn home_volume_command(volume: &str) -> Fallible<Option<Command>> {
+    let exists = cmd_podman()
+        .args(["volume", "inspect", volume])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?
+        .success();
+    if exists {
+        return Ok(None);
+    }
+    let mut podman = cmd_podman();
+    podman.args(["volume", "create", "--label=com.coreos.toolbox=true", volume]);
+    Ok(Some(podman))
+}
+
+/// Print the commands `create` runs for a new container, for
+/// `--show-command`.
+fn show_create_commands(
+    opts: &CreateOpts,
+    volumes: &[String],
+    snapshot: Option<&Snapshot>,
+) -> Fallible<()> {
+    if let Some(volume) = opts.home_volume() {
+        if let Some(podman) = home_volume_command(&volume)? {
+            println!("{}", format_command(&podman));
+        }
+    }
+    let podman = create_command(opts, volumes, snapshot)?;
+    println!("{}", format_command(&podman));
+    Ok(())
+}
+
+/// Create the toolbox container if it doesn't already exist, from
+/// the requested image or `snapshot`.
+fn create(opts: &CreateOpts, volumes: &[String], snapshot: Option<&Snapshot>) -> Fallible<()> {
+    if opts.show_command {
+        if !podman_has(InspectType::Container, &opts.container_name())? {
+            show_create_commands(opts, volumes, snapshot)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(arch) = opts.pullopts.podman_arch() {
+        check_foreign_arch(arch)?;
+    }
+    match snapshot {
+        // We've just committed it ourselves, so there's nothing to
+        // verify.
+        Some(s) if !podman_has(InspectType::Image, &s.image)? => {
+            bail!("Image {} is not present locally", s.image)
+        }
+        Some(_) => {}
+        None => ensure_image(&opts.image(), opts.pull_policy(), &opts.pullopts)?,
+    }
+
+    let name = opts.container_name();
+    if let Some(ref path) = opts.env_file {
+        // Check it now rather than on every enter.
+        read_env_file(path)?;
+        let dest = container_env_file(&name);
+        std::fs::create_dir_all(dest.parent().unwrap())?;
+        std::fs::copy(path, &dest)?;
+    }
+    if podman_has(InspectType::Container, &name)? {
+        return Ok(());
+    }
+
+    // Leftovers from containers which were removed without ever
+    // being entered.
+    remove_stale_statefiles()?;
+
+    if let Some(volume) = opts.home_volume() {
+        if let Some(mut podman) = home_volume_command(&volume)? {
+            podman.stdout(Stdio::null()).run()?;
+        }
+    }
+    let secrets = EntrypointSecrets::load()?;
+    if !secrets.is_empty() {
+        write_secrets_file(&name, &secrets)?;
+    }
+    let mut podman = create_command(opts, volumes, snapshot)?;
+    podman.stdout(Stdio::null());
+    podman.run()?;
+    // So that a toolbox which was created but never entered
+    // isn't immediately considered idle.
+    touch_last_used(&name)?;
+    Ok(())
+}
+
+fn cmd_create(opts: &CreateOpts) -> Fallible<()> {
+    opts.profile()?;
+    let name = opts.container_name();
+    if podman_has(InspectType::Container, &name)? {
+        bail!("Container {} already exists", name);
+    }
+    create(opts, &[], None)
+}
+
+fn in_container() -> bool {
+    Path::new("/run/.containerenv").exists()
+}
+
+/// Returns true if we're in one of our toolboxes, rather than some
+/// other container.
+fn in_toolbox() -> bool {
+    Path::new("/run/.toolboxenv").exists()
+}
+
+/// From inside a toolbox, run this invocation of coretoolbox on the
+/// host instead, as with host-exec.
+fn forward_to_host() -> Fallible<()> {
+    if find_in_path("flatpak-spawn").is_none() {
+        bail!("Already inside a toolbox; install flatpak-spawn in it to run coretoolbox on the host");
+    }
+    let mut command = vec!["coretoolbox".to_string()];
+    for arg in std::env::args_os().skip(1) {
+        let arg = arg
+            .into_string()
+            .map_err(|_| failure::err_msg("non-UTF8 argument"))?;
+        command.push(arg);
+    }
+    host_exec(&HostExecOpts { command })
+}
+
+fn run(opts: &RunOpts) -> Fallible<()> {
+    if in_container() && !opts.nested {
+        bail!("Already inside a container; use --nested to use podman in it anyway");
+    }
+
+    opts.create.profile()?;
+    let name = opts.create.container_name();
+    let name = name.as_str();
+
+    if opts.destroy {
+        if opts.create.show_command {
+            println!("{}", format_command(cmd_podman().args(["rm", "--force", name])));
+        } else {
+            rm(&RmOpts {
+                force: true,
+                containers: Vec::new(),
+                legacy_names: Vec::new(),
+                names: vec![name.to_string()],
+            })?;
+        }
+    }
+
+    if !opts.destroy && !opts.create.show_command && podman_has(InspectType::Container, name)? {
+        check_existing(&opts.create, name)?;
+    }
+
+    if opts.destroy && opts.create.show_command {
+        // The container would have been removed above.
+        show_create_commands(&opts.create, &[], None)?;
+    } else {
+        create(&opts.create, &[], None)?;
+    }
+
+    if opts.detach {
+        if !opts.command.is_empty() {
+            bail!("Cannot specify a command with --detach");
+        }
+        if opts.create.show_command {
+            println!("{}", format_command(&start_command(name)));
+            return Ok(());
+        }
+        return start_detached(name);
+    }
+
+    if opts.create.show_command {
+        println!("{}", format_command(&start_command(name)));
+        // The container may not have been created yet, in which case
+        // it will get the profile given on the command line.
+        let profile = if !opts.destroy && podman_has(InspectType::Container, name)? {
+            container_profile(name)?
+        } else {
+            opts.create.profile.clone()
+        };
+        let exec = exec_command(
+            name,
+            profile.as_deref(),
+            &opts.command,
+            false,
+            &opts.session,
+        )?;
+        println!("{}", format_command(&exec));
+        return Ok(());
+    }
+
+    enter_container(name, &opts.command, false, &opts.session)
+}
+
+/// `run` reuses an existing container of the same name; make sure
+/// that's what the user wants.  A toolbox from a different image
+/// requested with `--image` can be replaced interactively.
+fn check_existing(opts: &CreateOpts, name: &str) -> Fallible<()> {
+    if !is_toolbox_container(name)? {
+        bail!(
+            "Container {} exists but was not created by coretoolbox; \
+             use --destroy to replace it, or choose another name with -c",
+            name
+        );
+    }
+    if opts.image.is_none() {
+        return Ok(());
+    }
+    let requested = opts.image();
+    let image = container_label(name, "com.coreos.toolbox.image")?;
+    if image.is_empty() || image == requested {
+        return Ok(());
+    }
+    let prompt = format!(
+        "Container {} was created from {}, not {}; replace it?",
+        name, image, requested
+    );
+    if !nix::unistd::isatty(0)? || !confirm(&prompt)? {
+        bail!(
+            "Container {} was created from {}; use --destroy to recreate it from {}",
+            name,
+            image,
+            requested
+        );
+    }
+    if is_container_running(name)? {
+        bail!("Container {} is running; stop it first", name);
+    }
+    rm(&RmOpts {
+        force: false,
+        containers: Vec::new(),
+        legacy_names: Vec::new(),
+        names: vec![name.to_string()],
+    })
+}
+
+/// Start the container and run the entrypoint's initialization,
+/// without attaching to it; it keeps running in the background.
+fn start_detached(name: &str) -> Fallible<()> {
+    start_command(name).stdout(Stdio::null()).run()?;
+    cmd_podman()
+        .args(["exec", name, "/usr/bin/toolbox", "exec", "--init-only"])
+        .args(init_command_args()?)
+        .run()
+}
+
+/// Arguments passing the configured init commands to the entrypoint,
+/// which runs them if the container has just started.  They're not
+/// part of its state, so that changes apply without recreating it.
+fn init_command_args() -> Fallible<Vec<String>> {
+    Ok(config()?
+        .init_commands
+        .iter()
+        .flat_map(|c| vec!["--init-command".to_string(), c.clone()])
+        .collect())
+}
+
+fn start_command(name: &str) -> Command {
+    let mut podman = cmd_podman();
+    podman.args(["start", name]);
+    podman
+}
+
+/// Build the `podman exec` invocation running `command` (or an
+/// interactive shell if empty) via our entrypoint, as the toolbox
+/// user or root, with the environment of `profile` if any.
+fn exec_command(
+    name: &str,
+    profile: Option<&str>,
+    command: &[String],
+    root: bool,
+    session: &SessionOpts,
+) -> Fallible<Command> {
+    let mut podman = cmd_podman();
+    podman.args(["exec", "--interactive"]);
+    // Only allocate a terminal if we have one, so that commands
+    // can be used from scripts and pipelines.
+    if command.is_empty() || nix::unistd::isatty(0)? {
+        podman.arg("--tty");
+    }
+    // The profile's environment comes first, so that the toolbox's
+    // own environment file can override it.
+    let mut file_env = Vec::new();
+    if let Some(profile) = profile {
+        if let Some(p) = config()?.profile.get(profile) {
+            file_env.extend(p.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+    let env_file = container_env_file(name);
+    if env_file.exists() {
+        file_env.extend(read_env_file(&env_file)?);
+    }
+    append_env(&mut podman, session, &file_env)?;
+    podman.args([name, "/usr/bin/toolbox", "exec"]);
+    if root {
+        podman.arg("--root");
+    }
+    podman.args(init_command_args()?);
+    // The entrypoint uses the first of these the image has, falling
+    // back to bash or sh.
+    if command.is_empty() {
+        let configured = match session.shell {
+            Some(ref shell) => Some(shell.clone()),
+            None => config()?.default_shell.clone(),
+        };
+        let mut shells: Vec<String> = configured.into_iter().collect();
+        if let Ok(shell) = std::env::var("SHELL") {
+            if !shell.is_empty() && !shells.contains(&shell) {
+                shells.push(shell);
+            }
+        }
+        for shell in &shells {
+            podman.args(["--shell", shell.as_str()]);
+        }
+        if let Some(install) = config()?.install_shell {
+            podman.args(["--install-shell", install.as_str()]);
+        }
+        if !session.quiet && config()?.motd.unwrap_or(true) {
+            podman.arg("--motd");
+        }
+    }
+    // The entrypoint works out where this is in the toolbox.
+    if let Some(cwd) = std::env::current_dir().ok().and_then(|d| d.to_str().map(String::from)) {
+        podman.args(["--workdir", cwd.as_str()]);
+    }
+    if !command.is_empty() {
+        podman.arg("--");
+        podman.args(command);
+    }
+    Ok(podman)
+}
+
+/// Run the configured pre-enter command on the host, e.g. to start
+/// a VPN or refresh Kerberos tickets.  It gets the container's name
+/// and image as TOOLBOX_NAME and TOOLBOX_IMAGE, and if it fails we
+/// don't enter.
+fn run_pre_enter_hook(name: &str) -> Fallible<()> {
+    let hook = match config()?.pre_enter {
+        Some(ref hook) => hook,
+        None => return Ok(()),
+    };
+    let r = Command::new("/bin/sh")
+        .args(["-c", hook.as_str()])
+        .env("TOOLBOX_NAME", name)
+        .env("TOOLBOX_IMAGE", container_label(name, "com.coreos.toolbox.image")?)
+        .status()?;
+    if !r.success() {
+        bail!("pre-enter hook failed: {}", r);
+    }
+    Ok(())
+}
+
+/// Start the container if necessary, and run `command` (or an
+/// interactive shell if empty) inside it.  The exit status of the
+/// command becomes ours.
+fn enter_container(
+    name: &str,
+    command: &[String],
+    root: bool,
+    session: &SessionOpts,
+) -> Fallible<()> {
+    run_pre_enter_hook(name)?;
+    touch_last_used(name)?;
+    start_command(name).stdout(Stdio::null()).run()?;
+    let profile = container_profile(name)?;
+    let exec = exec_command(name, profile.as_deref(), command, root, session)?;
+    let status = run_session(exec)?;
+    std::process::exit(status)
+}
+
+/// Run `podman exec` in the foreground, passing on signals sent to
+/// us, rather than exec it, so we can clean up after it.  Returns
+/// its exit status, as a shell would report it.
+///
+/// Signals from the terminal (Ctrl-C, window resizes) already reach
+/// podman, being in the same process group, so SIGINT is only caught,
+/// not passed on again.
+fn run_session(mut podman: Command) -> Fallible<i32> {
+    use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+    // Catching these also stops them from killing us first.
+    let signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    let mut child = podman
+        .spawn()
+        .with_context(|e| format!("Running podman: {}", e))?;
+    let pid = nix::unistd::Pid::from_raw(child.id() as i32);
+    let forwarder = signals.clone();
+    let forwarder = std::thread::spawn(move || {
+        for signal in forwarder.forever().filter(|&s| s != SIGINT) {
+            if let Ok(signal) = nix::sys::signal::Signal::from_c_int(signal) {
+                let _ = nix::sys::signal::kill(pid, signal);
+            }
+        }
+    });
+    let status = child.wait()?;
+    signals.close();
+    let _ = forwarder.join();
+    Ok(match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    })
+}
+
+fn enter(opts: &EnterOpts) -> Fallible<()> {
+    if in_container() && !opts.nested {
+        bail!("Already inside a container; use --nested to use podman in it anyway");
+    }
+
+    let name = match opts.container.get() {
+        Some(name) => name.to_string(),
+        // E.g. from a project's configuration
+        None if config()?.name.is_some() => DEFAULT_CONTAINER_NAME.clone(),
+        None => {
+            let mut containers = list_containers()?;
+            match containers.len() {
+                0 => DEFAULT_CONTAINER_NAME.clone(),
+                1 => containers.remove(0).name,
+                _ => picker::pick_container(&containers)?,
+            }
+        }
+    };
+
+    if !podman_has(InspectType::Container, &name)? {
+        bail!(
+            "No toolbox container {}; use `coretoolbox create` first",
+            name
+        );
+    }
+    require_toolbox_container(&name)?;
+
+    enter_container(&name, &[], opts.root, &opts.session)
+}
+
+/// The container names given on the command line, with `-c` or as
+/// positional arguments.
+fn given_names(containers: &[String], names: &[String]) -> Vec<String> {
+    containers.iter().chain(names).cloned().collect()
+}
+
+/// The container named in $CORETOOLBOX_CONTAINER, which, as for
+/// `ContainerOpts`, applies when none is given on the command line.
+fn env_container() -> Option<String> {
+    std::env::var("CORETOOLBOX_CONTAINER").ok().filter(|n| !n.is_empty())
+}
+
+/// The container names given on the command line, or in the
+/// environment, or the default.
+fn names_or_default(containers: &[String], names: &[String]) -> Vec<String> {
+    let names = given_names(containers, names);
+    if !names.is_empty() {
+        return names;
+    }
+    vec![env_container().unwrap_or_else(|| DEFAULT_CONTAINER_NAME.clone())]
+}
+
+/// A toolbox container, as reported by `podman ps`.
+#[derive(Debug)]
+struct ContainerInfo {
+    name: String,
+    image: String,
+    status: String,
+    created: String,
+}
+
+/// Query podman for all containers created by us.
+fn list_containers() -> Fallible<Vec<ContainerInfo>> {
+    let out = cmd_podman()
+        .args([
+            "ps",
+            "--all",
+            "--filter",
+            TOOLBOX_FILTER,
+            "--format",
+            "{{.Names}}\t{{.Image}}\t{{.Status}}\t{{.CreatedAt}}",
+        ])
+        .run_get_output()?;
+    out.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let mut fields = l.splitn(4, '\t').map(|s| s.to_string());
+            let mut next = || {
+                fields
+                    .next()
+                    .ok_or_else(|| failure::format_err!("Invalid podman ps output: {}", l))
+            };
+            Ok(ContainerInfo {
+                name: next()?,
+                image: next()?,
+                status: next()?,
+                created: next()?,
+            })
+        })
+        .collect()
+}
+
+/// Names of all toolbox containers that are currently running.
+fn list_running_containers() -> Fallible<Vec<String>> {
+    let mut names = Vec::new();
+    for c in list_containers()? {
+        if is_container_running(&c.name)? {
+            names.push(c.name);
+        }
+    }
+    Ok(names)
+}
+
+/// Print rows as left-aligned columns under the given headers.
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (w, v) in widths.iter_mut().zip(row) {
+            *w = std::cmp::max(*w, v.len());
+        }
+    }
+    let print_row = |row: &[&str]| {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(v, w)| format!("{:1$}", v, *w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(headers);
+    for row in rows {
+        let row: Vec<&str> = row.iter().map(|s| s.as_str()).collect();
+        print_row(&row);
+    }
+}
+
+/// Records when a toolbox was last entered, as the modification
+/// time of a file in its data directory; labels can't be changed
+/// after creation.
+fn last_used_path(name: &str) -> std::path::PathBuf {
+    container_data_dir(name).join("last-used")
+}
+
+fn touch_last_used(name: &str) -> Fallible<()> {
+    let path = last_used_path(name);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, "")?;
+    Ok(())
+}
+
+/// Whole days since the toolbox was last entered, or `None` if
+/// we have no record of it ever being used.
+fn days_since_last_used(name: &str) -> Fallible<Option<u64>> {
+    let mtime = match std::fs::metadata(last_used_path(name)) {
+        Ok(m) => m.modified()?,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // A clock that went backwards counts as just now.
+    let elapsed = mtime.elapsed().unwrap_or_default();
+    Ok(Some(elapsed.as_secs() / (24 * 60 * 60)))
+}
+
+fn format_last_used(days: Option<u64>) -> String {
+    match days {
+        None => "never".to_string(),
+        Some(0) => "today".to_string(),
+        Some(1) => "yesterday".to_string(),
+        Some(n) => format!("{} days ago", n),
+    }
+}
+
+fn list() -> Fallible<()> {
+    let mut containers: Vec<Vec<String>> = Vec::new();
+    for c in list_containers()? {
+        let last_used = format_last_used(days_since_last_used(&c.name)?);
+        containers.push(vec![c.name, c.image, c.status, c.created, last_used]);
+    }
+    print_table(
+        &["NAME", "IMAGE", "STATUS", "CREATED", "LAST USED"],
+        &containers,
+    );
+
+    let out = cmd_podman()
+        .args([
+            "images",
+            "--filter",
+            TOOLBOX_FILTER,
+            "--format",
+            "{{.Repository}}:{{.Tag}}\t{{.ID}}\t{{.Created}}",
+        ])
+        .run_get_output()?;
+    let images: Vec<Vec<String>> = out
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.splitn(3, '\t').map(|s| s.to_string()).collect())
+        .collect();
+    println!();
+    print_table(&["IMAGE", "ID", "CREATED"], &images);
+    Ok(())
+}
+
+fn du() -> Fallible<()> {
+    // The size of a container is that of its writable layer; podman
+    // appends the size of the image as "(virtual ...)".
+    let out = cmd_podman()
+        .args([
+            "ps",
+            "--all",
+            "--size",
+            "--filter",
+            TOOLBOX_FILTER,
+            "--format",
+            "{{.Names}}\t{{.Size}}",
+        ])
+        .run_get_output()?;
+    let containers: Vec<Vec<String>> = out
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.splitn(2, '\t').map(|s| s.to_string()).collect())
+        .collect();
+    print_table(&["CONTAINER", "SIZE"], &containers);
+
+    let mut images: Vec<Vec<String>> = Vec::new();
+    for label in TOOLBOX_IMAGE_LABELS {
+        let out = cmd_podman()
+            .args([
+                "images",
+                "--filter",
+                &format!("label={}=true", label),
+                "--format",
+                "{{.Repository}}:{{.Tag}}\t{{.ID}}\t{{.Size}}",
+            ])
+            .run_get_output()?;
+        for l in out.lines().filter(|l| !l.is_empty()) {
+            let row: Vec<String> = l.splitn(3, '\t').map(|s| s.to_string()).collect();
+            if !images.contains(&row) {
+                images.push(row);
+            }
+        }
+    }
+    println!();
+    print_table(&["IMAGE", "ID", "SIZE"], &images);
+    Ok(())
+}
+
+/// Create an image from a container, labeled as a toolbox image.
+/// If the container was created by us, the image it was created
+/// from is recorded too.
+fn commit_container(name: &str, image: &str) -> Fallible<()> {
+    let mut podman = cmd_podman();
+    podman.args(["commit", "--change", "LABEL com.coreos.toolbox=true"]);
+    let base = container_label(name, "com.coreos.toolbox.image")?;
+    if !base.is_empty() {
+        podman.arg("--change");
+        podman.arg(format!("LABEL com.coreos.toolbox.base-image={}", base));
+    }
+    podman.args([name, image]).stdout(Stdio::null()).run()
+}
+
+fn export(opts: &ExportOpts) -> Fallible<()> {
+    if !["oci-archive", "docker-archive"].contains(&opts.format.as_str()) {
+        bail!("Unsupported archive format: {}", opts.format);
+    }
+    require_toolbox_container(opts.container.name())?;
+    let output = opts
+        .output
+        .to_str()
+        .ok_or_else(|| failure::err_msg("non-UTF8 output path"))?;
+    let image = format!("localhost/coretoolbox-export-{}:latest", opts.container.name());
+    commit_container(opts.container.name(), &image)?;
+    let r = cmd_podman()
+        .args(["save", "--format", opts.format.as_str(), "--output", output])
+        .arg(&image)
+        .run();
+    // The intermediate image is of no further use either way
+    cmd_podman()
+        .args(["rmi", image.as_str()])
+        .stdout(Stdio::null())
+        .run()?;
+    r
+}
+
+fn commit(opts: &CommitOpts) -> Fallible<()> {
+    require_toolbox_container(&opts.container)?;
+    commit_container(&opts.container, &opts.image)?;
+    println!("Created toolbox image {}", opts.image);
+    Ok(())
+}
+
+fn build(opts: &BuildOpts) -> Fallible<()> {
+    cmd_podman()
+        .args([
+            "build",
+            "--label=com.coreos.toolbox=true",
+            "--file",
+            opts.file.as_str(),
+            "--tag",
+            opts.tag.as_str(),
+            opts.context.as_str(),
+        ])
+        .run()?;
+    if let Some(ref name) = opts.create {
+        cmd_create(&CreateOpts {
+            image: Some(opts.tag.clone()),
+            name: Some(name.clone()),
+            ..Default::default()
+        })?;
+    }
+    Ok(())
+}
+
+/// Parse a `cp` argument into its container name (if any) and path.
+/// An empty name (as in `:/path`) means the default container; host
+/// paths containing a colon are recognized by a `/` before the colon.
+fn parse_cp_arg(arg: &str) -> (Option<String>, &str) {
+    match arg.split_once(':') {
+        Some((name, path)) if !name.contains('/') => {
+            let name = if name.is_empty() {
+                DEFAULT_CONTAINER_NAME.as_str()
+            } else {
+                name
+            };
+            (Some(name.to_string()), path)
+        }
+        _ => (None, arg),
+    }
+}
+
+fn cp(opts: &CpOpts) -> Fallible<()> {
+    let mut args = Vec::new();
+    let mut have_container = false;
+    for arg in &[&opts.src, &opts.dest] {
+        match parse_cp_arg(arg) {
+            (Some(name), path) => {
+                require_toolbox_container(&name)?;
+                have_container = true;
+                args.push(format!("{}:{}", name, path));
+            }
+            (None, path) => args.push(path.to_string()),
+        }
+    }
+    if !have_container {
+        bail!("One of the paths must be in a toolbox, as NAME:PATH");
+    }
+    cmd_podman().arg("cp").args(&args).run()
+}
+
+/// CRIU support in podman is only available for root.
+fn ensure_root_for_criu() -> Fallible<()> {
+    if !nix::unistd::getuid().is_root() {
+        bail!("Checkpoint/restore requires running coretoolbox as root");
+    }
+    Ok(())
+}
+
+fn checkpoint(opts: &CheckpointOpts) -> Fallible<()> {
+    ensure_root_for_criu()?;
+    require_toolbox_container(opts.container.name())?;
+    let mut podman = cmd_podman();
+    // We use the host network, so there'll often be open connections.
+    podman.args(["container", "checkpoint", "--tcp-established"]);
+    if let Some(ref export) = opts.export {
+        podman.args(["--export", export.as_str()]);
+    }
+    podman.arg(opts.container.name()).stdout(Stdio::null()).run()
+}
+
+fn restore(opts: &RestoreOpts) -> Fallible<()> {
+    ensure_root_for_criu()?;
+    let mut podman = cmd_podman();
+    podman.args(["container", "restore", "--tcp-established"]);
+    if let Some(ref import) = opts.import {
+        if podman_has(InspectType::Container, opts.container.name())? {
+            bail!("Container {} already exists; remove it first", opts.container.name());
+        }
+        podman.args(["--import", import.as_str(), "--name", opts.container.name()]);
+    } else {
+        require_toolbox_container(opts.container.name())?;
+        podman.arg(opts.container.name());
+    }
+    podman.stdout(Stdio::null()).run()
+}
+
+fn doctor() -> Fallible<()> {
+    let mut problems = 0;
+    let mut report = |ok: bool, what: &str, fix: &str| {
+        if ok {
+            println!("ok:      {}", what);
+        } else {
+            problems += 1;
+            println!("problem: {}", what);
+            println!("         {}", fix);
+        }
+    };
+
+    match cmd_podman().arg("--version").output() {
+        Ok(ref out) if out.status.success() => report(
+            true,
+            String::from_utf8_lossy(&out.stdout).trim(),
+            "",
+        ),
+        _ => report(
+            false,
+            "podman is not installed",
+            "Install podman, e.g. `dnf install podman`",
+        ),
+    }
+
+    let uid: u32 = nix::unistd::getuid().into();
+    let username = getenv_required_utf8("USER").unwrap_or_default();
+    if uid != 0 {
+        for (path, tool) in &[("/etc/subuid", "--add-subuids"), ("/etc/subgid", "--add-subgids")] {
+            match read_subid_range(path, &username, uid)? {
+                Some((start, count)) if count >= MAX_UID_COUNT => report(
+                    true,
+                    &format!("{}: {} ids starting at {}", path, count, start),
+                    "",
+                ),
+                Some((_, count)) => report(
+                    false,
+                    &format!("{}: only {} ids allocated to {}", path, count, username),
+                    &format!(
+                        "Allocate at least {} ids, e.g. `usermod {} 100000-165535 {}`",
+                        MAX_UID_COUNT, tool, username
+                    ),
+                ),
+                None => report(
+                    false,
+                    &format!("{}: no range allocated to {}", path, username),
+                    &format!("Run `usermod {} 100000-165535 {}`", tool, username),
+                ),
+            }
+        }
+    }
+
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        report(true, "cgroups v2", "");
+    } else {
+        report(true, "cgroups v1", "");
+    }
+
+    report(
+        uid == 0 || find_in_path("fuse-overlayfs").is_some(),
+        "fuse-overlayfs is available",
+        "Install fuse-overlayfs for efficient rootless storage",
+    );
+
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(ref d) if Path::new(d).is_dir() => {
+            report(true, &format!("XDG_RUNTIME_DIR is {}", d), "")
+        }
+        _ => report(
+            false,
+            "XDG_RUNTIME_DIR is unset or missing",
+            "Log in via a systemd session (e.g. ssh or a desktop login), not su",
+        ),
+    }
+
+    match std::fs::read_to_string("/sys/fs/selinux/enforce") {
+        Ok(ref v) if v.trim() == "1" => report(true, "SELinux is enforcing", ""),
+        Ok(_) => report(true, "SELinux is permissive", ""),
+        Err(_) => report(true, "SELinux is disabled", ""),
+    }
+
+    if problems > 0 {
+        bail!("Found {} problem(s)", problems);
+    }
+    Ok(())
+}
+
+fn generate_systemd(opts: &GenerateSystemdOpts) -> Fallible<()> {
+    require_toolbox_container(opts.container.name())?;
+    let podman = match std::env::var_os("podman") {
+        Some(p) => std::path::PathBuf::from(p),
+        None => find_in_path("podman").unwrap_or_else(|| "/usr/bin/podman".into()),
+    };
+    let podman = podman.display();
+    // Like start_detached(), so the toolbox is initialized too.
+    let init: String = init_command_args()?
+        .iter()
+        .map(|a| format!(" {}", systemd_quote(a)))
+        .collect();
+    print!(
+        "# Generated by coretoolbox; install as
+# ~/.config/systemd/user/coretoolbox-{name}.service and enable with
+# systemctl --user enable coretoolbox-{name}.service
+# Generate it again after changing init-commands.
+[Unit]
+Description=coretoolbox container {name}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={podman} start {name}
+ExecStartPost={podman} exec {name} /usr/bin/toolbox exec --init-only{init}
+ExecStop={podman} stop --time 10 {name}
+
+[Install]
+WantedBy=default.target
+",
+        name = opts.container.name(),
+        podman = podman,
+        init = init
+    );
+    Ok(())
+}
+
+/// Quote a string as one argument in a systemd unit's command line,
+/// where `$` and `%` are expanded too.
+fn systemd_quote(s: &str) -> String {
+    let s = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "$$")
+        .replace('%', "%%");
+    format!("\"{}\"", s)
+}
+
+/// Subcommands taking toolbox container names as arguments.
+static NAME_SUBCOMMANDS: &[&str] = &[
+    "enter", "rm", "stop", "upgrade", "status", "rename", "logs", "ps", "stats", "export",
+    "checkpoint", "restore",
+];
+
+fn completion(opts: &CompletionOpts) -> Fallible<()> {
+    let mut stdout = std::io::stdout();
+    Cli::clap().gen_completions_to("coretoolbox", opts.shell, &mut stdout);
+    // clap only knows about static values; add completion of
+    // container names where we can.
+    let names = format!(
+        "podman ps --all --filter {} --format '{{{{.Names}}}}' 2>/dev/null",
+        TOOLBOX_FILTER
+    );
+    match opts.shell {
+        clap::Shell::Bash => {
+            write!(
+                stdout,
+                "
+_coretoolbox_names() {{
+    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"
+    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+    if [[ \"$prev\" == -c || \"$prev\" == --container ]]; then
+        COMPREPLY=( $(compgen -W \"$({names})\" -- \"$cur\") )
+        return 0
+    fi
+    if [[ $COMP_CWORD -ge 2 && \"$cur\" != -* ]]; then
+        case \"${{COMP_WORDS[1]}}\" in
+            {subcommands})
+                COMPREPLY=( $(compgen -W \"$({names})\" -- \"$cur\") )
+                return 0;;
+        esac
+    fi
+    _coretoolbox \"$@\"
+}}
+complete -F _coretoolbox_names -o bashdefault -o default coretoolbox
+",
+                subcommands = NAME_SUBCOMMANDS.join("|"),
+                names = names
+            )?;
+        }
+        clap::Shell::Fish => {
+            writeln!(
+                stdout,
+                "complete -c coretoolbox -n '__fish_seen_subcommand_from {}' -f -a \"({})\"",
+                NAME_SUBCOMMANDS.join(" "),
+                names
+            )?;
+            writeln!(
+                stdout,
+                "complete -c coretoolbox -s c -l container -x -a \"({})\"",
+                names
+            )?;
+        }
+        clap::Shell::Zsh => {
+            write!(
+                stdout,
+                "
+_coretoolbox_names() {{
+    if [[ \"${{words[CURRENT-1]}}\" == (-c|--container) ]] ||
+       [[ $CURRENT -ge 3 && \"${{words[CURRENT]}}\" != -* && \"${{words[2]}}\" == ({subcommands}) ]]; then
+        compadd -- ${{(f)\"$({names})\"}}
+        return
+    fi
+    _coretoolbox \"$@\"
+}}
+compdef _coretoolbox_names coretoolbox
+",
+                subcommands = NAME_SUBCOMMANDS.join("|"),
+                names = names
+            )?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Render help output as a man page.
+fn help_to_man(name: &str, about: &str, help: &str) -> String {
+    let mut r = format!(
+        ".TH {} 1\n.SH NAME\n{} \\- {}\n.SH DESCRIPTION\n.nf\n",
+        name.to_uppercase(),
+        name.replace('-', "\\-"),
+        about
+    );
+    for line in help.lines() {
+        let line = line.replace('\\', "\\e");
+        if line.starts_with('.') || line.starts_with('\'') {
+            r.push_str("\\&");
+        }
+        r.push_str(&line);
+        r.push('\n');
+    }
+    r.push_str(".fi\n");
+    r
+}
+
+fn man(opts: &ManOpts) -> Fallible<()> {
+    let mut app = Cli::clap().set_term_width(80);
+    let mut help = Vec::new();
+    app.write_long_help(&mut help)?;
+    let help = String::from_utf8(help)?;
+    let page = help_to_man("coretoolbox", "Toolbox", &help);
+    let outdir = match opts.output_dir {
+        Some(ref d) => d,
+        None => {
+            print!("{}", page);
+            return Ok(());
+        }
+    };
+    std::fs::write(outdir.join("coretoolbox.1"), page)?;
+
+    // clap doesn't let us walk the subcommands, so parse them
+    // out of the (unwrapped) help text and ask clap for each one's help.
+    let mut unwrapped = Vec::new();
+    Cli::clap()
+        .set_term_width(0)
+        .write_long_help(&mut unwrapped)?;
+    let unwrapped = String::from_utf8(unwrapped)?;
+    let subcommands = unwrapped
+        .lines()
+        .skip_while(|l| !l.starts_with("SUBCOMMANDS:"))
+        .skip(1)
+        .filter_map(|l| {
+            let l = l.trim();
+            let (name, about) = l.split_once(' ')?;
+            Some((name.to_string(), about.trim().to_string()))
+        })
+        .filter(|(name, about)| name != "help" && !about.starts_with("Internal"));
+    for (name, about) in subcommands {
+        let e = match Cli::clap()
+            .set_term_width(80)
+            .get_matches_from_safe(["coretoolbox", name.as_str(), "--help"])
+        {
+            Err(e) => e,
+            Ok(_) => bail!("Failed to get help for {}", name),
+        };
+        let fullname = format!("coretoolbox-{}", name);
+        std::fs::write(
+            outdir.join(format!("{}.1", fullname)),
+            help_to_man(&fullname, &about, &e.message),
+        )?;
+    }
+    Ok(())
+}
+
+/// Exit status of `check-update` when updates are available, as
+/// with `dnf check-update`.
+static UPDATES_AVAILABLE_EXIT_STATUS: i32 = 100;
+
+fn check_update() -> Fallible<()> {
+    let mut stale = 0;
+    for c in list_containers()? {
+        let (image, _, mut digest) = container_image(&c.name)?;
+        // A container recreated from a snapshot by `mount` records
+        // the digest of the image it was originally created from.
+        let created_digest = container_label(&c.name, "com.coreos.toolbox.image-digest")?;
+        if !created_digest.is_empty() {
+            digest = created_digest;
+        }
+        match remote_image_digest(&image)? {
+            Some(ref remote) if *remote == digest => {}
+            Some(_) => {
+                println!("{}: newer {} available", c.name, image);
+                stale += 1;
+            }
+            None => bail!("skopeo is required to check for updates"),
+        }
+    }
+    if stale > 0 {
+        std::process::exit(UPDATES_AVAILABLE_EXIT_STATUS);
+    }
+    Ok(())
+}
+
+/// Repository of the upstream Fedora toolbox images.
+static FEDORA_TOOLBOX_REPO: &str = "registry.fedoraproject.org/fedora-toolbox";
+
+/// List the tags of a repository using skopeo; `None` if skopeo
+/// isn't installed.
+fn list_remote_tags(repo: &str) -> Fallible<Option<Vec<String>>> {
+    check_online("query the registry")?;
+    let out = match Command::new("skopeo")
+        .args(["list-tags", &format!("docker://{}", repo)])
+        .stderr(Stdio::inherit())
+        .output()
+    {
+        Ok(out) => out,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !out.status.success() {
+        bail!("Failed to list tags of {}: {}", repo, out.status);
+    }
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    Ok(Some(
+        v["Tags"]
+            .as_array()
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|t| t.as_str().map(|t| t.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    ))
+}
+
+fn search(opts: &SearchOpts) -> Fallible<()> {
+    match list_remote_tags(FEDORA_TOOLBOX_REPO)? {
+        Some(tags) => {
+            let mut releases: Vec<u32> = tags.iter().filter_map(|t| t.parse().ok()).collect();
+            releases.sort_unstable();
+            println!("Fedora toolbox releases:");
+            for r in releases {
+                println!("  {}:{}", FEDORA_TOOLBOX_REPO, r);
+            }
+        }
+        None => println!("Install skopeo to list Fedora toolbox releases"),
+    }
+    println!();
+    let out = cmd_podman()
+        .args([
+            "search",
+            "--format",
+            "{{.Name}}\t{{.Description}}",
+            opts.term.as_str(),
+        ])
+        .run_get_output()?;
+    let rows: Vec<Vec<String>> = out
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.splitn(2, '\t').map(|s| s.to_string()).collect())
+        .collect();
+    print_table(&["IMAGE", "DESCRIPTION"], &rows);
+    Ok(())
+}
+
+fn init_image(opts: &InitImageOpts) -> Fallible<()> {
+    let mut f = std::fs::OpenOptions::new();
+    f.write(true);
+    if opts.force {
+        f.create(true).truncate(true);
+    } else {
+        f.create_new(true);
+    }
+    let f = f
+        .open(&opts.output)
+        .with_context(|e| format!("Creating {}: {}", opts.output, e))?;
+    let mut f = std::io::BufWriter::new(f);
+    write!(
+        f,
+        "# Build with: coretoolbox build -f {output} -t my-toolbox
+FROM {image}
+LABEL com.coreos.toolbox=true
+# The coretoolbox entrypoint needs these to set up your user
+RUN dnf -y install shadow-utils sudo util-linux && dnf clean all
+# Add the packages you want in your toolbox here, e.g.:
+# RUN dnf -y install gcc make gdb && dnf clean all
+",
+        output = opts.output,
+        image = resolve_image(&opts.image)
+    )?;
+    f.flush()?;
+    println!("Wrote {}", opts.output);
+    Ok(())
+}
+
+/// Podman can't add mounts to an existing container, so commit it
+/// to an image and recreate it from that with the new mount.
+fn mount(opts: &MountOpts) -> Fallible<()> {
+    let name = opts.container.as_str();
+    require_toolbox_container(name)?;
+    let source = std::fs::canonicalize(&opts.source)
+        .with_context(|e| format!("Resolving {}: {}", opts.source, e))?;
+    let source = source
+        .to_str()
+        .ok_or_else(|| failure::err_msg("non-UTF8 source path"))?;
+    let dest = opts.dest.as_deref().unwrap_or(source);
+    let mut volumes = container_volumes(name)?;
+    volumes.push(format!("{}:{}:rslave", source, dest));
+
+    if is_container_running(name)? {
+        println!("Stopping {}", name);
+        stop(&StopOpts {
+            all: false,
+            time: 10,
+            containers: Vec::new(),
+            names: vec![name.to_string()],
+        })?;
+    }
+    let (image, _, digest) = container_image(name)?;
+    let snapshot = Snapshot {
+        image: format!("localhost/coretoolbox-state-{}:latest", name),
+        digest: match container_label(name, "com.coreos.toolbox.image-digest")? {
+            ref d if d.is_empty() => digest,
+            d => d,
+        },
+    };
+    commit_container(name, &snapshot.image)?;
+    let createopts = CreateOpts::recreate(name, &image)?;
+    remove_for_recreate(name)?;
+    create(&createopts, &volumes, Some(&snapshot))?;
+    println!("Mounted {} at {} in {}", source, dest, name);
+    Ok(())
+}
+
+fn load(opts: &LoadOpts) -> Fallible<()> {
+    let input = opts
+        .input
+        .to_str()
+        .ok_or_else(|| failure::err_msg("non-UTF8 input path"))?;
+    let out = cmd_podman()
+        .args(["load", "--input", input])
+        .run_get_output()?;
+    // Depending on the version, podman prints "Loaded image: NAME"
+    // or "Loaded image(s): NAME,NAME".
+    let images: Vec<String> = out
+        .lines()
+        .filter(|l| l.starts_with("Loaded image"))
+        .filter_map(|l| l.split_once(": ").map(|(_, v)| v))
+        .flat_map(|l| l.split(','))
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if images.is_empty() {
+        bail!("No images found in {}", input);
+    }
+    for image in &images {
+        if !is_toolbox_image(image)? {
+            // Label it, so it shows up as a toolbox image.
+            let tmpname = format!("coretoolbox-load-{}", std::process::id());
+            let mut create = cmd_podman();
+            create.args(["create", "--name", tmpname.as_str(), image.as_str()]);
+            // It's never run, but podman won't create a container
+            // from an image that has no command at all.
+            if !image_has_command(image)? {
+                create.arg("true");
+            }
+            create.stdout(Stdio::null()).run()?;
+            let r = commit_container(&tmpname, image);
+            cmd_podman()
+                .args(["rm", tmpname.as_str()])
+                .stdout(Stdio::null())
+                .run()?;
+            r?;
+        }
+        println!("Loaded toolbox image {}", image);
+    }
+    Ok(())
+}
+
+/// Remove a container that is about to be recreated, keeping our
+/// records for it.
+fn remove_for_recreate(name: &str) -> Fallible<()> {
+    cmd_podman()
+        .args(["rm", "--force", name])
+        .stdout(Stdio::null())
+        .run()?;
+    // The new container won't read one left by the old.
+    remove_statefiles(|n| Ok(n == name))
+}
+
+fn rm(opts: &RmOpts) -> Fallible<()> {
+    for name in &names_or_default(&opts.containers, &opts.names) {
+        if !podman_has(InspectType::Container, name)? {
+            if opts.force {
+                continue;
+            }
+            bail!("No such container: {}", name);
+        }
+        if !opts.force {
+            if !is_toolbox_container(name)? {
+                bail!("Container {} was not created by coretoolbox; use --force", name);
+            }
+            if is_container_running(name)? {
+                bail!("Container {} is running; use --force", name);
+            }
+        }
+        let mut podman = cmd_podman();
+        podman.arg("rm");
+        if opts.force {
+            podman.arg("--force");
+        }
+        podman.arg(name).stdout(Stdio::null()).run()?;
+        remove_dir_all_if_exists(&container_data_dir(name))?;
+        remove_statefiles(|n| Ok(n == name))?;
+    }
+    Ok(())
+}
+
+fn stop(opts: &StopOpts) -> Fallible<()> {
+    let names = if opts.all {
+        if !given_names(&opts.containers, &opts.names).is_empty() {
+            bail!("Cannot specify both --all and container names");
+        }
+        list_running_containers()?
+    } else {
+        names_or_default(&opts.containers, &opts.names)
+    };
+    for name in &names {
+        require_toolbox_container(name)?;
+    }
+    if names.is_empty() {
+        return Ok(());
+    }
+    // podman sends SIGTERM, and SIGKILL once the timeout expires.
+    cmd_podman()
+        .args(["stop", "--time", &opts.time.to_string()])
+        .args(&names)
+        .stdout(Stdio::null())
+        .run()
+}
+
+fn upgrade(opts: &UpgradeOpts) -> Fallible<()> {
+    let name = opts.container.name();
+    require_toolbox_container(name)?;
+    let image = container_label(name, "com.coreos.toolbox.image")?;
+    if image.is_empty() {
+        bail!("Container {} does not record its image", name);
+    }
+    let old_id = podman_inspect(InspectType::Container, name, "{{.Image}}")?;
+    let mut pullopts = opts.pullopts.clone();
+    if pullopts.arch.is_none() {
+        pullopts.arch = container_arch(name)?;
+    }
+    pull_image(&image, &pullopts)?;
+    let new_id = podman_inspect(InspectType::Image, &image, "{{.Id}}")?;
+    if old_id == new_id {
+        println!("{} is already using the latest {}", name, image);
+        return Ok(());
+    }
+
+    let volumes = container_volumes(name)?;
+    let createopts = CreateOpts::recreate(name, &image)?;
+    remove_for_recreate(name)?;
+    create(&createopts, &volumes, None)?;
+    println!("Upgraded {} ({})", name, image);
+    println!("  old image: {}", old_id);
+    println!("  new image: {}", new_id);
+    Ok(())
+}
+
+/// Ask the registry for the current digest of an image, using skopeo.
+/// Returns `None` if skopeo isn't installed.
+fn remote_image_digest(image: &str) -> Fallible<Option<String>> {
+    check_online("query the registry")?;
+    let out = match Command::new("skopeo")
+        .args(["inspect", &format!("docker://{}", image)])
+        .stderr(Stdio::inherit())
+        .output()
+    {
+        Ok(out) => out,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !out.status.success() {
+        bail!("Failed to inspect {} in the registry: {}", image, out.status);
+    }
+    let v: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    Ok(v["Digest"].as_str().map(|s| s.to_string()))
+}
+
+/// Returns the image reference a container was created from, along
+/// with the ID and digest of the image it is actually using.
+fn container_image(name: &str) -> Fallible<(String, String, String)> {
+    let mut image = container_label(name, "com.coreos.toolbox.image")?;
+    if image.is_empty() {
+        image = podman_inspect(InspectType::Container, name, "{{.ImageName}}")?;
+    }
+    let image_id = podman_inspect(InspectType::Container, name, "{{.Image}}")?;
+    let digest = podman_inspect(InspectType::Image, &image_id, "{{.Digest}}")?;
+    Ok((image, image_id, digest))
+}
+
+fn status(opts: &StatusOpts) -> Fallible<()> {
+    let name = opts.container.name();
+    if !podman_has(InspectType::Container, name)? {
+        println!("{}: does not exist", name);
+        return Ok(());
+    }
+    let out = cmd_podman()
+        .args(["inspect", "--type", "container", name])
+        .run_get_output()?;
+    let v: serde_json::Value = serde_json::from_str(&out)?;
+    let v = &v[0];
+    let (image, image_id, digest) = container_image(name)?;
+
+    println!("Name:    {}", name);
+    if !is_toolbox_container(name)? {
+        println!("         (not created by coretoolbox)");
+    }
+    println!("State:   {}", v["State"]["Status"].as_str().unwrap_or("unknown"));
+    println!("Image:   {}", image);
+    println!("ID:      {}", image_id);
+    println!("Digest:  {}", digest);
+    let created_digest = container_label(name, "com.coreos.toolbox.image-digest")?;
+    if !created_digest.is_empty() && created_digest != digest {
+        println!("         (created from {})", created_digest);
+    }
+    if let Some(profile) = container_profile(name)? {
+        println!("Profile: {}", profile);
+    }
+    if let Some(volume) = container_home_volume(name)? {
+        println!("Home: volume {}", volume);
+    }
+    let version = container_label(name, "com.coreos.toolbox.version")?;
+    if !version.is_empty() {
+        let host_os = container_label(name, "com.coreos.toolbox.host-os")?;
+        if host_os.is_empty() {
+            println!("Created: by coretoolbox {}", version);
+        } else {
+            println!("Created: by coretoolbox {} on {}", version, host_os);
+        }
+    }
+    let uidmap = container_label(name, "com.coreos.toolbox.uidmap")?;
+    if !uidmap.is_empty() {
+        println!("UID map: {}", uidmap);
+    }
+    println!("Mounts:");
+    if let Some(mounts) = v["Mounts"].as_array() {
+        for m in mounts {
+            println!(
+                "  {} -> {}",
+                m["Source"].as_str().unwrap_or(""),
+                m["Destination"].as_str().unwrap_or("")
+            );
+        }
+    }
+    let update = match remote_image_digest(&image) {
+        Ok(Some(ref remote)) if *remote == digest => "up to date".to_string(),
+        Ok(Some(remote)) => format!("newer image available ({})", remote),
+        Ok(None) => "unknown (skopeo is not installed)".to_string(),
+        Err(e) => format!("unknown ({})", e),
+    };
+    println!("Update:  {}", update);
+    Ok(())
+}
+
+fn remove_dir_all_if_exists(p: &Path) -> Fallible<()> {
+    match std::fs::remove_dir_all(p) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Directory holding our per-container records, keyed by container name.
+fn container_data_dir(name: &str) -> std::path::PathBuf {
+    APPDIRS.data_dir().join("containers").join(name)
+}
+
+/// Rename a container, carrying along our own records for it.
+/// Note the com.coreos.toolbox.name label can't be changed after
+/// creation; it records the name the container was created with.
+fn rename(opts: &RenameOpts) -> Fallible<()> {
+    require_toolbox_container(&opts.old)?;
+    if podman_has(InspectType::Container, &opts.new)? {
+        bail!("Container {} already exists", opts.new);
+    }
+    cmd_podman()
+        .args(["rename", opts.old.as_str(), opts.new.as_str()])
+        .run()?;
+    let old_data = container_data_dir(&opts.old);
+    if old_data.exists() {
+        std::fs::rename(&old_data, container_data_dir(&opts.new))?;
+    }
+    Ok(())
+}
+
+fn logs(opts: &LogsOpts) -> Fallible<()> {
+    require_toolbox_container(opts.container.name())?;
+    let mut podman = cmd_podman();
+    podman.arg("logs");
+    if opts.follow {
+        podman.arg("--follow");
+    }
+    podman.arg(opts.container.name());
+    Err(podman.exec().into())
+}
+
+fn ps(opts: &PsOpts) -> Fallible<()> {
+    require_toolbox_container(opts.container.name())?;
+    // `huser` is the user on the host, which podman derives from the
+    // container's uid_map; with the mapping set up in create() the
+    // toolbox user shows up as the invoking host user.
+    Err(cmd_podman()
+        .args(["top", opts.container.name(), "pid", "user", "huser", "etime", "args"])
+        .exec()
+        .into())
+}
+
+fn stats(opts: &StatsOpts) -> Fallible<()> {
+    let mut names = given_names(&opts.containers, &opts.names);
+    if names.is_empty() {
+        names.extend(env_container());
+    }
+    if names.is_empty() {
+        names = list_running_containers()?;
+        if names.is_empty() {
+            bail!("No running toolbox containers");
+        }
+    }
+    let mut podman = cmd_podman();
+    podman.arg("stats");
+    if opts.json {
+        podman.args(["--no-stream", "--format", "json"]);
+    }
+    podman.args(&names);
+    Err(podman.exec().into())
+}
+
+/// Ask a yes/no question on the terminal.
+fn confirm(prompt: &str) -> Fallible<bool> {
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(["y", "yes"].contains(&answer.trim().to_lowercase().as_str()))
+}
+
+/// Remove statefiles left behind in XDG_RUNTIME_DIR by containers
+/// that were never entered: the EntrypointSecrets files, and the
+/// state older versions passed that way.
+fn remove_statefiles<F: Fn(&str) -> Fallible<bool>>(filter: F) -> Fallible<()> {
+    let runtime_dir = match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(d) => d,
+        Err(_) => return Ok(()),
+    };
+    for e in std::fs::read_dir(runtime_dir)? {
+        let e = e?;
+        let name = match e.file_name().to_str() {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        if let Some(container) = name
+            .strip_prefix("coreos-toolbox-")
+            .and_then(|n| n.strip_suffix(".initdata").or_else(|| n.strip_suffix(".secrets")))
+        {
+            if filter(container)? {
+                std::fs::remove_file(e.path())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove statefiles for containers that no longer exist.
+fn remove_stale_statefiles() -> Fallible<()> {
+    remove_statefiles(|name| Ok(!podman_has(InspectType::Container, name)?))
+}
+
+fn gc(opts: &GcOpts) -> Fallible<()> {
+    remove_stale_statefiles()?;
+    if opts.statefiles_only {
+        return Ok(());
+    }
+    let mut exited = Vec::new();
+    for c in list_containers()? {
+        if podman_inspect(InspectType::Container, &c.name, "{{.State.Status}}")? == "exited" {
+            exited.push(c.name);
+        }
+    }
+    for name in &exited {
+        println!("Removing exited container {}", name);
+    }
+    if exited.is_empty() {
+        return Ok(());
+    }
+    rm(&RmOpts {
+        force: false,
+        containers: Vec::new(),
+        legacy_names: Vec::new(),
+        names: exited,
+    })
+}
+
+/// Run a podman listing command and return the non-empty lines.
+fn podman_lines(args: &[&str]) -> Fallible<Vec<String>> {
+    Ok(cmd_podman()
+        .args(args)
+        .run_get_output()?
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect())
+}
+
+fn prune(opts: &PruneOpts) -> Fallible<()> {
+    let mut containers = Vec::new();
+    for c in list_containers()? {
+        if is_container_running(&c.name)? {
+            continue;
+        }
+        if let Some(idle_days) = opts.idle_days {
+            // Containers with no record predate tracking, so we
+            // can't tell; treat them as idle.
+            match days_since_last_used(&c.name)? {
+                Some(days) if days < idle_days => continue,
+                _ => {}
+            }
+        }
+        containers.push(c.name);
+    }
+    let images = podman_lines(&[
+        "images",
+        "--filter",
+        TOOLBOX_FILTER,
+        "--filter",
+        "dangling=true",
+        "--format",
+        "{{.ID}}",
+    ])?;
+    let volumes = podman_lines(&[
+        "volume",
+        "ls",
+        "--filter",
+        TOOLBOX_FILTER,
+        "--filter",
+        "dangling=true",
+        "--format",
+        "{{.Name}}",
+    ])?;
+
+    for (kind, items) in &[("container", &containers), ("image", &images), ("volume", &volumes)] {
+        for item in items.iter() {
+            if opts.dry_run {
+                println!("Would remove {} {}", kind, item);
+            } else {
+                println!("Removing {} {}", kind, item);
+            }
+        }
+    }
+    if opts.dry_run {
+        return Ok(());
+    }
+    if !containers.is_empty() {
+        rm(&RmOpts {
+            force: false,
+            containers: Vec::new(),
+            legacy_names: Vec::new(),
+            names: containers,
+        })?;
+    }
+    if !images.is_empty() {
+        cmd_podman()
+            .arg("rmi")
+            .args(&images)
+            .stdout(Stdio::null())
+            .run()?;
+    }
+    if !volumes.is_empty() {
+        cmd_podman()
+            .args(["volume", "rm"])
+            .args(&volumes)
+            .stdout(Stdio::null())
+            .run()?;
+    }
+    Ok(())
+}
+
+fn reset(opts: &ResetOpts) -> Fallible<()> {
+    let containers = list_containers()?;
+    let images = list_image_ids(&["com.coreos.toolbox"])?;
+    let mut volumes = podman_lines(&[
+        "volume",
+        "ls",
+        "--filter",
+        TOOLBOX_FILTER,
+        "--format",
+        "{{.Name}}",
+    ])?;
+    // Home volumes created before they were labelled.
+    for c in &containers {
+        if let Some(v) = container_home_volume(&c.name)? {
+            if !volumes.contains(&v) {
+                volumes.push(v);
+            }
+        }
+    }
+    println!(
+        "This will remove {} container(s), {} image(s), {} volume(s), and all coretoolbox {}.",
+        containers.len(),
+        images.len(),
+        volumes.len(),
+        if opts.keep_config { "data" } else { "data and configuration" }
+    );
+    if !opts.yes && !confirm("Continue?")? {
+        bail!("Aborted");
+    }
+    if !containers.is_empty() {
+        rm(&RmOpts {
+            force: true,
+            containers: Vec::new(),
+            legacy_names: Vec::new(),
+            names: containers.into_iter().map(|c| c.name).collect(),
+        })?;
+    }
+    if !images.is_empty() {
+        rmi(&RmiOpts {
+            force: true,
+            unused: false,
+            images,
+        })?;
+    }
+    if !volumes.is_empty() {
+        cmd_podman()
+            .args(["volume", "rm", "--force"])
+            .args(&volumes)
+            .stdout(Stdio::null())
+            .run()?;
+    }
+    remove_statefiles(|_| Ok(true))?;
+    // As written by older versions, for all containers.
+    if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
+        match std::fs::remove_file(Path::new(&runtime_dir).join("coreos-toolbox.initdata")) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            r => r?,
+        }
+    }
+    for d in &[APPDIRS.cache_dir(), APPDIRS.data_dir()] {
+        remove_dir_all_if_exists(d)?;
+    }
+    if !opts.keep_config {
+        remove_dir_all_if_exists(APPDIRS.config_dir())?;
+    }
+    Ok(())
+}
+
+/// Return the full IDs of all images carrying any of the given labels.
+fn list_image_ids(labels: &[&str]) -> Fallible<Vec<String>> {
+    let mut ids = Vec::new();
+    for label in labels {
+        let out = cmd_podman()
+            .args([
+                "images",
+                "--no-trunc",
+                "--filter",
+                &format!("label={}=true", label),
+                "--format",
+                "{{.ID}}",
+            ])
+            .run_get_output()?;
+        for id in out.lines() {
+            let id = id.trim_start_matches("sha256:");
+            if !id.is_empty() && !ids.iter().any(|v| v == id) {
+                ids.push(id.to_string());
+            }
+        }
+    }
+    Ok(ids)
+}
+
+fn rmi(opts: &RmiOpts) -> Fallible<()> {
+    let mut images = opts.images.clone();
+    if opts.unused {
+        let mut used = Vec::new();
+        for c in list_containers()? {
+            used.push(podman_inspect(InspectType::Container, &c.name, "{{.Image}}")?);
+        }
+        images.extend(
+            list_image_ids(TOOLBOX_IMAGE_LABELS)?
+                .into_iter()
+                .filter(|id| !used.iter().any(|u| u.trim_start_matches("sha256:") == id)),
+        );
+    } else if images.is_empty() {
+        bail!("No images specified");
+    }
+    for image in &images {
+        if !opts.force && !is_toolbox_image(image)? {
+            bail!("Image {} is not a toolbox image; use --force", image);
+        }
+        let mut podman = cmd_podman();
+        podman.arg("rmi");
+        if opts.force {
+            podman.arg("--force");
+        }
+        podman.arg(image).run()?;
+    }
+    Ok(())
+}
+
+/// Run a command on the host.  From inside the container this goes
+/// via flatpak-spawn, which asks the Flatpak session helper on the
+/// host's session bus to spawn it; the fedora-toolbox images include it.
+fn host_exec(opts: &HostExecOpts) -> Fallible<()> {
+    let (prog, args) = match opts.command.split_first() {
+        Some(v) => v,
+        None => bail!("No command specified"),
+    };
+    if !in_container() {
+        return Err(Command::new(prog).args(args).exec().into());
+    }
+    let spawn = match find_in_path("flatpak-spawn") {
+        Some(p) => p,
+        None => bail!("flatpak-spawn is required in the toolbox to run commands on the host"),
+    };
+    Err(Command::new(spawn)
+        .arg("--host")
+        .args(&opts.command)
+        .exec()
+        .into())
+}
+
+/// How long to give the container's processes to exit when stopped,
+/// within podman's own timeout.
+static SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Act as the init of a toolbox container: reap the processes
+/// reparented to us, and when stopped, pass that on so that sessions
+/// get to exit cleanly rather than being killed with us.
+fn run_pid1() -> Fallible<()> {
+    use signal_hook::{SIGCHLD, SIGINT, SIGTERM};
+    let signals = signal_hook::iterator::Signals::new([SIGCHLD, SIGTERM, SIGINT])?;
+    for signal in signals.forever() {
+        if signal == SIGCHLD {
+            waitpid_all();
+        } else {
+            shutdown_pid1();
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Ask everything else in the container to exit, and wait a while
+/// for it to.
+fn shutdown_pid1() {
+    // -1 means all processes in the namespace but us, which is only
+    // what we want if we're its init.
+    if std::process::id() != 1 {
+        return;
+    }
+    let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(-1), nix::sys::signal::SIGTERM);
+    let deadline = std::time::Instant::now() + SHUTDOWN_GRACE;
+    while std::time::Instant::now() < deadline && other_processes_remain() {
+        waitpid_all();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+/// Whether any processes but ours are in the container; those from
+/// `podman exec` aren't our children, so we can't wait for them.
+fn other_processes_remain() -> bool {
+    let ours = std::process::id().to_string();
+    std::fs::read_dir("/proc")
+        .map(|entries| {
+            entries.filter_map(|e| e.ok()).any(|e| {
+                let name = e.file_name();
+                let name = name.to_string_lossy();
+                name.bytes().all(|b| b.is_ascii_digit()) && name != ours
+            })
+        })
+        .unwrap_or(false)
+}
+
+fn waitpid_all() {
+    use nix::sys::wait::{WaitPidFlag, WaitStatus};
+    while let Ok(status) = nix::sys::wait::waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+        if status == WaitStatus::StillAlive {
+            break;
+        }
+    }
+}
+
+/// A minimal interactive selector for the terminal.
+mod picker {
+    use super::ContainerInfo;
+    use failure::{bail, Fallible};
+    use nix::sys::termios;
+    use std::io::prelude::*;
+
+    /// Restores the terminal mode when dropped.
+    struct RawMode(termios::Termios);
+
+    impl RawMode {
+        fn enable() -> Fallible<Self> {
+            let orig = termios::tcgetattr(0)?;
+            let mut raw = orig.clone();
+            termios::cfmakeraw(&mut raw);
+            termios::tcsetattr(0, termios::SetArg::TCSANOW, &raw)?;
+            Ok(RawMode(orig))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            let _ = termios::tcsetattr(0, termios::SetArg::TCSANOW, &self.0);
+        }
+    }
+
+    /// Let the user pick a container, using the arrow keys to move
+    /// and typing to filter by name or image.
+    pub(crate) fn pick_container(containers: &[ContainerInfo]) -> Fallible<String> {
+        if !nix::unistd::isatty(0)? || !nix::unistd::isatty(1)? {
+            let names: Vec<&str> = containers.iter().map(|c| c.name.as_str()).collect();
+            bail!("Multiple toolboxes exist, specify one of: {}", names.join(", "));
+        }
+        let raw = RawMode::enable()?;
+        let mut out = std::io::stdout();
+        let mut filter = String::new();
+        let mut selected = 0;
+        let mut drawn = 0;
+        loop {
+            let needle = filter.to_lowercase();
+            let matches: Vec<&ContainerInfo> = containers
+                .iter()
+                .filter(|c| {
+                    c.name.to_lowercase().contains(&needle)
+                        || c.image.to_lowercase().contains(&needle)
+                })
+                .collect();
+            selected = std::cmp::min(selected, matches.len().saturating_sub(1));
+
+            // Redraw over the previous rendering
+            if drawn > 0 {
+                write!(out, "\x1b[{}A", drawn)?;
+            }
+            write!(out, "\r\x1b[JSelect a toolbox (type to filter): {}\r\n", filter)?;
+            for (i, c) in matches.iter().enumerate() {
+                let line = format!("{}  {}  {}", c.name, c.image, c.status);
+                if i == selected {
+                    write!(out, "\x1b[7m> {}\x1b[0m\r\n", line)?;
+                } else {
+                    write!(out, "  {}\r\n", line)?;
+                }
+            }
+            drawn = matches.len() + 1;
+            out.flush()?;
+
+            let mut buf = [0u8; 3];
+            let n = std::io::stdin().read(&mut buf)?;
+            match &buf[..n] {
+                b"\r" | b"\n" => {
+                    if let Some(c) = matches.get(selected) {
+                        drop(raw);
+                        return Ok(c.name.clone());
+                    }
+                }
+                b"\x1b[A" => selected = selected.saturating_sub(1),
+                b"\x1b[B" => selected += 1,
+                [0x7f] | [0x08] => {
+                    filter.pop();
+                }
+                [0x03] | [0x1b] | [0x04] => {
+                    drop(raw);
+                    bail!("No toolbox selected");
+                }
+                [c] if c.is_ascii_graphic() => {
+                    filter.push(*c as char);
+                    selected = 0;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+mod entrypoint {
+    use super::CommandRunExt;
+    use super::EntrypointState;
+    use failure::{bail, Fallible, ResultExt};
     use fs2::FileExt;
     use rayon::prelude::*;
     use std::io::prelude::*;
     use std::os::unix;
+    use std::os::unix::fs::PermissionsExt;
     use std::os::unix::process::CommandExt;
-    use std::path::Path;
-    use std::process::Command;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
 
     static CONTAINER_INITIALIZED_LOCK: &str = "/run/coreos-toolbox.lock";
     static CONTAINER_INITIALIZED_STAMP: &str = "/run/coreos-toolbox.initialized";
+    /// Copy of the state kept in the container; /run is a tmpfs, so
+    /// we initialize again each time the container is started.
+    static CONTAINER_STATE: &str = "/var/lib/coreos-toolbox/state.json";
+    /// Records the packages which have been installed.
+    static PACKAGES_INSTALLED: &str = "/var/lib/coreos-toolbox/packages";
+    static POST_CREATE_HOOK: &str = "/var/lib/coreos-toolbox/post-create";
+    static POST_CREATE_DONE: &str = "/var/lib/coreos-toolbox/post-create.done";
+    /// Shells the user didn't want installed, one per line
+    static SHELLS_DECLINED: &str = "/var/lib/coreos-toolbox/shells-declined";
+    /// Marks the container as a toolbox, for scripts and the prompt;
+    /// ours has the container's name and image in the format of
+    /// podman's /run/.containerenv.
+    static TOOLBOX_ENV: &str = "/run/.toolboxenv";
+    static PROFILE_SNIPPET_PATH: &str = "/etc/profile.d/coretoolbox.sh";
+    static PROFILE_SNIPPET: &str = r#"# Written by coretoolbox on every start; changes will be lost.
+# Mark the prompt of interactive bash and zsh sessions in a toolbox.
+if [ -f /run/.toolboxenv ] && [ -n "${PS1-}" ] && [ -z "${CORETOOLBOX_PROMPT-}" ]; then
+    CORETOOLBOX_PROMPT=$(sed -n 's/^name="\(.*\)"$/\1/p' /run/.toolboxenv)
+    CORETOOLBOX_PROMPT=${CORETOOLBOX_PROMPT:-toolbox}
+    if [ -n "${BASH_VERSION-}" ]; then
+        PS1="⬢[\u@${CORETOOLBOX_PROMPT} \W]\\$ "
+    elif [ -n "${ZSH_VERSION-}" ]; then
+        PS1="⬢[%n@${CORETOOLBOX_PROMPT} %1~]%# "
+    fi
+fi
+"#;
+
+    /// Where ncurses looks for terminfo entries, on the various distros.
+    static TERMINFO_DIRS: &[&str] = &[
+        "/etc/terminfo",
+        "/lib/terminfo",
+        "/usr/share/terminfo",
+        "/usr/lib/terminfo",
+    ];
+    /// Where we put the scripts running host commands on the host;
+    /// it comes before /usr/bin in PATH.
+    static HOST_SHIM_DIR: &str = "/usr/local/bin";
+    static HOST_SHIM_MARKER: &str = "# Written by coretoolbox; runs the command on the host.";
+    /// Falls back to the image's command while flatpak-spawn, which
+    /// host-exec needs, isn't installed.
+    static HOST_SHIM: &str = r#"#!/bin/sh
+# Written by coretoolbox; runs the command on the host.
+if command -v flatpak-spawn >/dev/null 2>&1; then
+    exec /usr/bin/toolbox host-exec -- @COMMAND@ "$@"
+fi
+for p in /usr/bin/@COMMAND@ /bin/@COMMAND@; do
+    if [ -x "$p" ]; then
+        exec "$p" "$@"
+    fi
+done
+echo "@COMMAND@: install flatpak-spawn to run this on the host" >&2
+exit 127
+"#;
+    /// Present once the welcome message has been shown.
+    static MOTD_SHOWN: &str = "/var/lib/coreos-toolbox/motd-shown";
+    /// Locales we failed to generate, so we don't keep trying.
+    static LOCALES_UNAVAILABLE: &str = "/var/lib/coreos-toolbox/locales-unavailable";
+
+    /// Read the state passed from the host, and save it for the
+    /// sessions.  Containers from older versions got it from a
+    /// statefile in the runtime dir on first start.
+    fn load_state(runtime_dir: Option<&str>) -> Fallible<EntrypointState> {
+        let mut state: EntrypointState = if let Ok(state) = std::env::var("TOOLBOX_STATE") {
+            serde_json::from_str(&state).with_context(|e| format!("Parsing TOOLBOX_STATE: {}", e))?
+        } else {
+            let statefile = super::getenv_required_utf8("TOOLBOX_STATEFILE")?;
+            let runtime_dir = match runtime_dir {
+                Some(d) => d,
+                None => return saved_state(),
+            };
+            let p = format!("/host/{}/{}", runtime_dir, statefile);
+            let contents = match std::fs::read_to_string(&p) {
+                Ok(c) => c,
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return saved_state(),
+                Err(e) => return Err(failure::format_err!("Reading statefile: {}", e)),
+            };
+            // Whether or not it's any good, it's of no further use.
+            std::fs::remove_file(p)?;
+            serde_json::from_str(&contents)
+                .with_context(|e| format!("Parsing statefile: {}", e))?
+        };
+        // The secrets aren't saved, as skip_serializing says; they're
+        // only needed on first start.
+        std::fs::create_dir_all(Path::new(CONTAINER_STATE).parent().unwrap())?;
+        let w = std::fs::File::create(CONTAINER_STATE)?;
+        let mut w = std::io::BufWriter::new(w);
+        serde_json::to_writer(&mut w, &state)?;
+        w.flush()?;
+        if let (Ok(file), Some(runtime_dir)) = (std::env::var("TOOLBOX_SECRETS"), runtime_dir) {
+            let p = format!("/host/{}/{}", runtime_dir, file);
+            match std::fs::read_to_string(&p) {
+                Ok(contents) => {
+                    std::fs::remove_file(&p)?;
+                    let secrets: super::EntrypointSecrets = serde_json::from_str(&contents)
+                        .with_context(|e| format!("Parsing {}: {}", p, e))?;
+                    state.post_create = secrets.post_create;
+                    state.password_hash = secrets.password_hash;
+                }
+                // Already used, on an earlier start.
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(failure::format_err!("Reading {}: {}", p, e)),
+            }
+        }
+        Ok(state)
+    }
+
+    /// The state saved by `load_state`.
+    fn saved_state() -> Fallible<EntrypointState> {
+        let f = std::fs::File::open(CONTAINER_STATE)
+            .with_context(|e| format!("Opening statefile: {}", e))?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+    }
 
     /// Update /etc/passwd with the same user from the host,
     /// and bind mount the homedir.
@@ -352,44 +4475,412 @@ mod entrypoint {
         if state.uid == 0 {
             return Ok(());
         }
-        let uidstr = format!("{}", state.uid);
-        Command::new("useradd")
-            .args(&[
-                "--no-create-home",
-                "--home-dir",
-                &state.home,
-                "--uid",
-                &uidstr,
-                "--groups",
-                "wheel",
-                state.username.as_str(),
-            ])
-            .run()?;
+        // The user persists if the container is recreated from a
+        // committed image.
+        let gid = state.gid.unwrap_or(state.uid);
+        if !user_exists(&state.username)? {
+            log(&format!("Adding user {} ({})", state.username, state.uid));
+            let shadow_utils = have_shadow_utils();
+            let login_shell = if Path::new("/bin/bash").exists() { "/bin/bash" } else { "/bin/sh" };
+            // Use the group with the host's gid, whatever it's called
+            // in the image, or create one named after us.  For older
+            // containers, useradd makes one.
+            if (state.gid.is_some() || !shadow_utils)
+                && !super::read_group_file("/etc/group")?
+                    .iter()
+                    .any(|(_, g)| *g == gid)
+            {
+                groupadd(&state.username, gid)?;
+            }
+            if shadow_utils {
+                let uidstr = format!("{}", state.uid);
+                let gidstr = format!("{}", gid);
+                let mut useradd = Command::new("useradd");
+                useradd.args([
+                    "--no-create-home",
+                    "--home-dir",
+                    &state.home,
+                    "--uid",
+                    &uidstr,
+                    // Debian's default is sh
+                    "--shell",
+                    login_shell,
+                ]);
+                if state.gid.is_some() {
+                    useradd.args(["--gid", &gidstr]);
+                }
+                useradd.arg(state.username.as_str()).run()?;
+            } else {
+                // E.g. a busybox based image
+                append_line(
+                    "/etc/passwd",
+                    &format!(
+                        "{}:x:{}:{}::{}:{}",
+                        state.username, state.uid, gid, state.home, login_shell
+                    ),
+                )?;
+            }
+        } else {
+            // E.g. the image has the user already, with its own idea
+            // of where the home directory is.
+            set_passwd_home(&state.username, &state.home)?;
+        }
+        // Either way, so that a user podman added for keep-id can
+        // use sudo too.
+        if let Some(group) = admin_group()? {
+            add_to_groups(&state.username, &[group])?;
+        }
 
-        // Bind mount the homedir rather than use symlinks
-        // as various software is unhappy if the path isn't canonical.
         std::fs::create_dir_all(&state.home)?;
         let uid = nix::unistd::Uid::from_raw(state.uid);
-        let gid = nix::unistd::Gid::from_raw(state.uid);
+        let gid = nix::unistd::Gid::from_raw(gid);
         nix::unistd::chown(state.home.as_str(), Some(uid), Some(gid))?;
+        if state.isolated_home {
+            return populate_home(state);
+        }
+        // Bind mount the homedir rather than use symlinks
+        // as various software is unhappy if the path isn't canonical.
         let host_home = format!("/host{}", state.home);
         Command::new("mount")
-            .args(&["--bind", host_home.as_str(), state.home.as_str()])
+            .args(["--bind", host_home.as_str(), state.home.as_str()])
             .run()?;
         Ok(())
     }
 
-    /// Symlink a path e.g. /run/dbus/system_bus_socket to the
-    /// /host equivalent, creating any necessary parent directories.
-    fn host_symlink<P: AsRef<Path> + std::fmt::Display>(p: P) -> Fallible<()> {
-        let path = p.as_ref();
-        std::fs::create_dir_all(path.parent().unwrap())?;
-        match std::fs::remove_dir_all(path) {
-            Ok(_) => Ok(()),
-            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-            Err(e) => Err(e),
-        }?;
-        unix::fs::symlink(format!("/host{}", p), path)?;
+    /// Which of the host's `groups` to create in the container, as
+    /// (name, gid), and which to add the user to, given the groups in
+    /// the container's /etc/group.  A group the image lacks is skipped
+    /// if its gid is taken there.
+    fn match_groups<'a>(
+        existing: &[(String, u32)],
+        groups: &'a [(String, u32)],
+    ) -> (Vec<(&'a str, u32)>, Vec<&'a str>) {
+        let mut create = Vec::new();
+        let mut join = Vec::new();
+        for (name, gid) in groups {
+            if existing.iter().any(|(n, _)| n == name) {
+                join.push(name.as_str());
+            } else if existing.iter().any(|(_, g)| g == gid) {
+                log(&format!("Not adding group {}: gid {} is in use", name, gid));
+            } else {
+                create.push((name.as_str(), *gid));
+                join.push(name.as_str());
+            }
+        }
+        (create, join)
+    }
+
+    /// Add the user to the same supplementary groups as on the host,
+    /// so that su's initgroups() finds them and device permissions
+    /// work the same.
+    fn add_groups(state: &EntrypointState) -> Fallible<()> {
+        if state.uid == 0 || state.groups.is_empty() {
+            return Ok(());
+        }
+        let existing = super::read_group_file("/etc/group")?;
+        let (create, join) = match_groups(&existing, &state.groups);
+        for (name, gid) in create {
+            groupadd(name, gid)?;
+        }
+        add_to_groups(&state.username, &join)
+    }
+
+    /// The image's distribution family, from its os-release: the ID
+    /// or one of ID_LIKE, e.g. "fedora" for CentOS or "debian" for
+    /// Ubuntu.
+    fn image_os_family() -> Option<String> {
+        let os_release = super::read_os_release("/etc/os-release")
+            .or_else(|_| super::read_os_release("/usr/lib/os-release"))
+            .ok()?;
+        let id = os_release.get("ID")?;
+        let like = os_release.get("ID_LIKE").map(|s| s.as_str()).unwrap_or("");
+        ["fedora", "debian", "arch", "suse", "alpine"]
+            .iter()
+            .find(|f| id == *f || like.split_whitespace().any(|l| l == **f))
+            .map(|f| f.to_string())
+            .or_else(|| Some(id.clone()))
+    }
+
+    /// The group whose members may administer the system: wheel,
+    /// or sudo on Debian and Ubuntu, if the image has it.
+    fn admin_group() -> Fallible<Option<&'static str>> {
+        let candidates: &[&'static str] = match image_os_family().as_deref() {
+            Some("debian") => &["sudo", "wheel"],
+            _ => &["wheel", "sudo"],
+        };
+        let existing = super::read_group_file("/etc/group")?;
+        Ok(candidates
+            .iter()
+            .find(|c| existing.iter().any(|(n, _)| n == **c))
+            .cloned())
+    }
+
+    /// Whether the image has shadow-utils; minimal images may not.
+    fn have_shadow_utils() -> bool {
+        ["useradd", "groupadd", "usermod"]
+            .iter()
+            .all(|c| super::find_in_path(c).is_some())
+    }
+
+    fn append_line(path: &str, line: &str) -> Fallible<()> {
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        writeln!(f, "{}", line)?;
+        Ok(())
+    }
+
+    /// Create a group, with groupadd if possible.
+    fn groupadd(name: &str, gid: u32) -> Fallible<()> {
+        if have_shadow_utils() {
+            Command::new("groupadd")
+                .args(["--gid", &gid.to_string(), name])
+                .run()
+        } else {
+            append_line("/etc/group", &format!("{}:x:{}:", name, gid))
+        }
+    }
+
+    /// Add a user to existing groups, with usermod if possible.
+    fn add_to_groups(username: &str, groups: &[&str]) -> Fallible<()> {
+        if groups.is_empty() {
+            return Ok(());
+        }
+        if have_shadow_utils() {
+            return Command::new("usermod")
+                .args(["--append", "--groups", &groups.join(","), username])
+                .run();
+        }
+        let contents = std::fs::read_to_string("/etc/group")?;
+        std::fs::write("/etc/group", group_with_members(&contents, username, groups))?;
+        Ok(())
+    }
+
+    /// The contents of /etc/group with the user added to `groups`.
+    fn group_with_members(contents: &str, username: &str, groups: &[&str]) -> String {
+        let mut out = String::new();
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split(':').collect();
+            match fields.as_slice() {
+                [name, pw, gid, members]
+                    if groups.contains(name) && !members.split(',').any(|m| m == username) =>
+                {
+                    let sep = if members.is_empty() { "" } else { "," };
+                    out.push_str(&format!("{}:{}:{}:{}{}{}", name, pw, gid, members, sep, username));
+                }
+                _ => out.push_str(line),
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Set the user's password field in /etc/shadow, adding an entry
+    /// if there is none.  This is done directly, so it works
+    /// without shadow-utils.
+    fn set_password(username: &str, hash: &str) -> Fallible<()> {
+        let path = "/etc/shadow";
+        let (contents, exists) = match std::fs::read_to_string(path) {
+            Ok(c) => (c, true),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (String::new(), false),
+            Err(e) => return Err(e.into()),
+        };
+        // Days since the epoch, for the date of the last change
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let today = now.as_secs() / 86400;
+        std::fs::write(path, shadow_with_password(&contents, username, hash, today))?;
+        if !exists {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o000))?;
+        }
+        Ok(())
+    }
+
+    /// The contents of /etc/shadow with the user's password set to
+    /// `hash`; a new entry was last changed on day `today`.
+    fn shadow_with_password(contents: &str, username: &str, hash: &str, today: u64) -> String {
+        let prefix = format!("{}:", username);
+        let mut found = false;
+        let mut lines: Vec<String> = contents
+            .lines()
+            .map(|l| match l.strip_prefix(&prefix) {
+                Some(rest) => {
+                    found = true;
+                    let rest = rest.split_once(':').map(|(_, r)| r).unwrap_or("");
+                    format!("{}{}:{}", prefix, hash, rest)
+                }
+                None => l.to_string(),
+            })
+            .collect();
+        if !found {
+            lines.push(format!("{}{}:{}::::::", prefix, hash, today));
+        }
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        contents
+    }
+
+    /// Copy /etc/skel into a new isolated home directory, as useradd
+    /// would have, so the shell starts with the image's defaults.
+    fn populate_home(state: &EntrypointState) -> Fallible<()> {
+        if std::fs::read_dir(&state.home)?.next().is_some() || !Path::new("/etc/skel").is_dir() {
+            return Ok(());
+        }
+        log(&format!("Populating {} from /etc/skel", state.home));
+        let dest = format!("{}/", state.home);
+        Command::new("cp").args(["-a", "/etc/skel/.", dest.as_str()]).run()?;
+        let owner = format!("{}:{}", state.uid, state.gid.unwrap_or(state.uid));
+        Command::new("chown").args(["-R", owner.as_str(), state.home.as_str()]).run()?;
+        Ok(())
+    }
+
+    /// Point the user's /etc/passwd entry at `home`, if it doesn't
+    /// already.
+    fn set_passwd_home(username: &str, home: &str) -> Fallible<()> {
+        let path = "/etc/passwd";
+        let contents = std::fs::read_to_string(path)?;
+        if let Some(contents) = passwd_with_home(&contents, username, home) {
+            log(&format!("Setting home directory of {} to {}", username, home));
+            std::fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+
+    /// The contents of /etc/passwd with the user's home directory set
+    /// to `home`, or None if it's already that (or there's no such user).
+    fn passwd_with_home(contents: &str, username: &str, home: &str) -> Option<String> {
+        let prefix = format!("{}:", username);
+        let mut changed = false;
+        let lines: Vec<String> = contents
+            .lines()
+            .map(|l| {
+                let mut fields: Vec<&str> = l.split(':').collect();
+                if !l.starts_with(&prefix) || fields.len() != 7 || fields[5] == home {
+                    return l.to_string();
+                }
+                changed = true;
+                fields[5] = home;
+                fields.join(":")
+            })
+            .collect();
+        if !changed {
+            return None;
+        }
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        Some(contents)
+    }
+
+    /// Returns true if the user has an entry in /etc/passwd.
+    fn user_exists(username: &str) -> Fallible<bool> {
+        let prefix = format!("{}:", username);
+        let passwd = std::fs::read_to_string("/etc/passwd")?;
+        Ok(passwd.lines().any(|l| l.starts_with(&prefix)))
+    }
+
+    fn is_symlink<P: AsRef<Path>>(p: P) -> bool {
+        std::fs::symlink_metadata(p)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false)
+    }
+
+    /// Symlink a path e.g. /run/dbus/system_bus_socket to the
+    /// /host equivalent, creating any necessary parent directories.
+    fn host_symlink<P: AsRef<Path> + std::fmt::Display>(p: P) -> Fallible<()> {
+        let path = p.as_ref();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        match std::fs::remove_dir_all(path) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }?;
+        unix::fs::symlink(format!("/host{}", p), path)?;
+        Ok(())
+    }
+
+    /// Bind mount the host's runtime dir (e.g. /run/user/1000) at the
+    /// same path; like the homedir, a symlink upsets software that
+    /// checks the path, ownership or mode.  If the host has none
+    /// there, make an empty one so at least it exists.
+    fn forward_runtime_dir(path: &str, state: &EntrypointState) -> Fallible<()> {
+        // E.g. under /tmp, which we've already forwarded.
+        if let Ok(p) = std::fs::canonicalize(path) {
+            if p.starts_with("/host") {
+                return Ok(());
+            }
+        }
+        std::fs::create_dir_all(path)?;
+        let uid = nix::unistd::Uid::from_raw(state.uid);
+        let gid = nix::unistd::Gid::from_raw(state.gid.unwrap_or(state.uid));
+        nix::unistd::chown(path, Some(uid), Some(gid))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))?;
+        let host_path = format!("/host{}", path);
+        if Path::new(&host_path).is_dir() {
+            Command::new("mount")
+                .args(["--bind", host_path.as_str(), path])
+                .run()?;
+        }
+        Ok(())
+    }
+
+    /// The container's current name, which changes with `rename`;
+    /// podman writes it to /run/.containerenv on each start.  The
+    /// name it was created with, for podman versions which don't.
+    fn live_name(state: &EntrypointState) -> Option<String> {
+        super::read_os_release("/run/.containerenv")
+            .ok()
+            .and_then(|env| env.get("name").cloned())
+            .filter(|n| !n.is_empty())
+            .or_else(|| state.name.clone())
+    }
+
+    fn write_toolbox_env(state: &EntrypointState) -> Fallible<()> {
+        let mut contents = String::new();
+        if let Some(name) = live_name(state) {
+            contents.push_str(&format!("name=\"{}\"\n", name));
+        }
+        if let Some(ref image) = state.image {
+            contents.push_str(&format!("image=\"{}\"\n", image));
+        }
+        std::fs::write(TOOLBOX_ENV, contents)?;
+        if Path::new("/etc/profile.d").is_dir() {
+            std::fs::write(PROFILE_SNIPPET_PATH, PROFILE_SNIPPET)?;
+        }
+        Ok(())
+    }
+
+    /// Where `path` on the host really is under /host, following
+    /// symlinks such as /etc/resolv.conf -> /run/systemd/resolve/...
+    /// which would otherwise resolve inside the container.
+    fn resolve_host_path(path: &str) -> Fallible<PathBuf> {
+        let mut path = PathBuf::from(path);
+        for _ in 0..16 {
+            let host_path = Path::new("/host").join(path.strip_prefix("/")?);
+            if !is_symlink(&host_path) {
+                return Ok(host_path);
+            }
+            let target = std::fs::read_link(&host_path)?;
+            path = path.parent().unwrap_or_else(|| Path::new("/")).join(target);
+        }
+        bail!("Too many levels of symbolic links: {}", path.display())
+    }
+
+    /// Replace a file in the container, perhaps one podman generated,
+    /// with a symlink to the host's, so that it follows changes, even
+    /// where the file is replaced rather than rewritten.
+    fn forward_host_file(path: &str) -> Fallible<()> {
+        let target = resolve_host_path(path)?;
+        if !target.exists() {
+            return Ok(());
+        }
+        // It's usually bind mounted; if not, this fails harmlessly.
+        let _ = Command::new("umount").arg(path).stderr(Stdio::null()).status();
+        match std::fs::remove_file(path) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        unix::fs::symlink(target, path)?;
         Ok(())
     }
 
@@ -401,13 +4892,13 @@ mod entrypoint {
         let sysfs_selinux = "/sys/fs/selinux";
         if Path::new(sysfs_selinux).join("status").exists() {
             Command::new("mount")
-                .args(&["--bind", "/usr/share/empty", sysfs_selinux])
+                .args(["--bind", "/usr/share/empty", sysfs_selinux])
                 .run()?;
         }
         Ok(())
     }
 
-    fn init_container() -> Fallible<()> {
+    fn init_container(init_commands: &[String]) -> Fallible<()> {
         let initstamp = Path::new(CONTAINER_INITIALIZED_STAMP);
         if initstamp.exists() {
             return Ok(());
@@ -417,19 +4908,15 @@ mod entrypoint {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(CONTAINER_INITIALIZED_LOCK)?;
         lockf.lock_exclusive()?;
+        log("Initializing container");
 
         workaround_podman_selinux()?;
 
-        let runtime_dir = super::getenv_required_utf8("XDG_RUNTIME_DIR")?;
-        let state: EntrypointState = {
-            let p = format!("/host/{}/{}", runtime_dir, "coreos-toolbox.initdata");
-            let f =
-                std::fs::File::open(&p).with_context(|e| format!("Opening statefile: {}", e))?;
-            std::fs::remove_file(p)?;
-            serde_json::from_reader(std::io::BufReader::new(f))?
-        };
+        let runtime_dir = std::env::var("XDG_RUNTIME_DIR").ok();
+        let state = load_state(runtime_dir.as_deref())?;
 
         let var_mnt_dirs = ["/srv", "/mnt"];
         if state.ostree_based_host {
@@ -437,25 +4924,33 @@ mod entrypoint {
             .try_for_each(|d| -> Fallible<()> {
                 let hostd = format!("/host{}", d);
                 let vard = format!("var{}", d);
-                unix::fs::symlink(vard, hostd)?;
+                if !is_symlink(&hostd) {
+                    unix::fs::symlink(vard, hostd)?;
+                }
                 Ok(())
             })?;
         }
 
-        // Remove anaconda cruft
-        std::fs::read_dir("/tmp")?.try_for_each(|e| -> Fallible<()> {
-            let e = e?;
-            if let Some(name) = e.file_name().to_str() {
-                if name.starts_with("ks-script-") {
-                    std::fs::remove_file(e.path())?;
+        // Remove anaconda cruft; if /tmp is already forwarded, this
+        // is a restart and we'd be looking at the host's /tmp.
+        if !is_symlink("/tmp") {
+            std::fs::read_dir("/tmp")?.try_for_each(|e| -> Fallible<()> {
+                let e = e?;
+                if let Some(name) = e.file_name().to_str() {
+                    if name.starts_with("ks-script-") {
+                        std::fs::remove_file(e.path())?;
+                    }
                 }
-            }
-            Ok(())
-        })?;
+                Ok(())
+            })?;
+        }
 
         // Propagate data and temporary directories to the host
         var_mnt_dirs.par_iter().chain(["/tmp", "/var/tmp"].par_iter())
             .try_for_each(|d| -> Fallible<()> {
+                if is_symlink(d) {
+                    return Ok(());
+                }
                 std::fs::remove_dir(d)?;
                 let hostd = format!("/host{}", d);
                 unix::fs::symlink(hostd, d)?;
@@ -464,7 +4959,10 @@ mod entrypoint {
             .with_context(|e| format!("Symlinking host dir: {}", e))?;
 
         // And forward the runtime dir
-        host_symlink(runtime_dir).with_context(|e| format!("Forwarding runtime dir: {}", e))?;
+        if let Some(ref runtime_dir) = runtime_dir {
+            forward_runtime_dir(runtime_dir, &state)
+                .with_context(|e| format!("Forwarding runtime dir: {}", e))?;
+        }
 
         // These symlinks into /host are our set of default forwarded APIs/state
         // directories.
@@ -473,6 +4971,28 @@ mod entrypoint {
             .try_for_each(host_symlink)
             .with_context(|e| format!("Enabling static host forwards: {}", e))?;
 
+        write_toolbox_env(&state).with_context(|e| format!("Writing {}: {}", TOOLBOX_ENV, e))?;
+
+        // Podman writes its own copies of these, which go stale when
+        // e.g. a VPN changes the host's DNS servers.
+        for f in super::HOST_NETWORK_FILES {
+            if let Err(e) = forward_host_file(f) {
+                log(&format!("warning: forwarding {}: {}", f, e));
+            }
+        }
+        // Tools such as journalctl expect the host's; some prefer to
+        // keep it private.
+        if state.share_machine_id {
+            if let Err(e) = forward_host_file("/etc/machine-id") {
+                log(&format!("warning: forwarding /etc/machine-id: {}", e));
+            }
+        }
+        // Use the host's timezone; the symlink points at a zoneinfo
+        // file under /host, so the image needn't have tzdata.
+        if let Err(e) = forward_host_file("/etc/localtime") {
+            log(&format!("warning: forwarding /etc/localtime: {}", e));
+        }
+
         // And these are into /dev
         super::FORWARDED_DEVICES
             .par_iter()
@@ -484,65 +5004,1180 @@ mod entrypoint {
                 Ok(())
             })
             .with_context(|e| format!("Forwarding devices: {}", e))?;
+        // Those asked for explicitly are linked even if they don't
+        // exist yet, so that e.g. a serial adapter can be plugged in
+        // later.
+        for d in &state.devices {
+            let p = Path::new(d);
+            if p.exists() || is_symlink(p) {
+                continue;
+            }
+            if let Some(parent) = p.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            unix::fs::symlink(format!("/host{}", d), p)
+                .with_context(|e| format!("Forwarding device {}: {}", d, e))?;
+        }
 
-        // Allow sudo
+        // Allow sudo; the user has no password to give it.
         || -> Fallible<()> {
-            let f = std::fs::File::create(format!("/etc/sudoers.d/toolbox-{}", state.username))?;
+            let path = format!("/etc/sudoers.d/toolbox-{}", state.username);
+            if !state.passwordless_sudo.unwrap_or(true) {
+                // It may be left from an image committed earlier.
+                match std::fs::remove_file(&path) {
+                    Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        bail!("Removing {}: {}", path, e)
+                    }
+                    _ => return Ok(()),
+                }
+            }
+            std::fs::create_dir_all("/etc/sudoers.d")?;
+            let f = std::fs::File::create(&path)?;
             let mut f = std::io::BufWriter::new(f);
             writeln!(&mut f, "{} ALL=(ALL) NOPASSWD: ALL", state.username)?;
             f.flush()?;
+            // sudo ignores rules in files others can write to.
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o440))?;
             Ok(())
         }()
         .with_context(|e| format!("Enabling sudo: {}", e))?;
 
         adduser(&state)?;
-        let _ = std::fs::File::create(&initstamp)?;
+        add_groups(&state).with_context(|e| format!("Adding groups: {}", e))?;
+        if let Some(ref hash) = state.password_hash {
+            set_password(&state.username, hash)
+                .with_context(|e| format!("Setting password: {}", e))?;
+        }
+        if state.dnf_proxy {
+            copy_dnf_proxy().with_context(|e| format!("Copying dnf proxy settings: {}", e))?;
+        }
+        install_packages(&state.packages)?;
+        if let Some(ref hook) = state.post_create {
+            run_post_create(hook, &state.username)?;
+        }
+        install_host_shims(&state).with_context(|e| format!("Installing host commands: {}", e))?;
+        run_init_commands(init_commands, &state.username);
+        let _ = std::fs::File::create(initstamp)?;
 
         Ok(())
     }
 
-    pub(crate) fn exec() -> Fallible<()> {
-        use nix::sys::stat::Mode;
+    /// Add the proxy settings from the [main] section of the host's
+    /// dnf.conf to ours, unless we have some already.
+    fn copy_dnf_proxy() -> Fallible<()> {
+        let conf = Path::new("/etc/dnf/dnf.conf");
+        let host_conf = Path::new("/host/etc/dnf/dnf.conf");
+        if !conf.exists() || !host_conf.exists() {
+            return Ok(());
+        }
+        let is_proxy = |l: &str| l.trim_start().starts_with("proxy");
+        let mut section = String::new();
+        let mut settings = Vec::new();
+        for line in std::fs::read_to_string(host_conf)?.lines() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                section = line.to_string();
+            } else if section == "[main]" && is_proxy(line) {
+                settings.push(line.to_string());
+            }
+        }
+        let ours = std::fs::read_to_string(conf)?;
+        if settings.is_empty() || ours.lines().any(is_proxy) {
+            return Ok(());
+        }
+        log("Copying proxy settings from the host's dnf.conf");
+        let mut out = String::new();
+        for line in ours.lines() {
+            out.push_str(line);
+            out.push('\n');
+            if line.trim() == "[main]" {
+                for s in &settings {
+                    out.push_str(s);
+                    out.push('\n');
+                }
+            }
+        }
+        std::fs::write(conf, out)?;
+        Ok(())
+    }
+
+    /// Install the configured packages with whichever package manager
+    /// the image has.  The list is recorded in PACKAGES_INSTALLED, so
+    /// this only happens once per container.  A failure is reported
+    /// but doesn't make the toolbox unusable; we'll try again on the
+    /// next start.
+    fn install_packages(packages: &[String]) -> Fallible<()> {
+        if packages.is_empty() {
+            return Ok(());
+        }
+        // Containers created before package names were checked.
+        if let Some(p) = packages.iter().find(|p| super::check_package_name(p).is_err()) {
+            log(&format!("warning: not installing packages: invalid package name {}", p));
+            return Ok(());
+        }
+        let wanted = packages.join("\n");
+        if std::fs::read_to_string(PACKAGES_INSTALLED).ok().as_deref() == Some(wanted.as_str()) {
+            return Ok(());
+        }
+        let steps = match package_install_steps(packages) {
+            Some(steps) => steps,
+            None => {
+                log("warning: no supported package manager found; not installing packages");
+                return Ok(());
+            }
+        };
+        log(&format!("Installing packages: {}", packages.join(" ")));
+        eprintln!("Installing packages: {}", packages.join(" "));
+        // Output goes to the terminal of whoever started the
+        // first session.
+        for mut step in steps {
+            if let Err(e) = step.run() {
+                log(&format!("warning: installing packages: {}", e));
+                eprintln!("warning: installing packages failed; will retry on next start");
+                return Ok(());
+            }
+        }
+        std::fs::write(PACKAGES_INSTALLED, wanted)?;
+        Ok(())
+    }
+
+    /// The commands to install packages with the image's package
+    /// manager, if we know it.
+    fn package_install_steps(packages: &[String]) -> Option<Vec<Command>> {
+        let install = |manager: &str| {
+            let mut c = Command::new(manager);
+            c.args(["-y", "install", "--"]).args(packages);
+            c
+        };
+        Some(if Path::new("/usr/bin/dnf").exists() {
+            vec![install("dnf")]
+        } else if Path::new("/usr/bin/microdnf").exists() {
+            vec![install("microdnf")]
+        } else if Path::new("/usr/bin/apt-get").exists() {
+            let mut update = Command::new("apt-get");
+            update.arg("update");
+            let mut install = install("apt-get");
+            install.env("DEBIAN_FRONTEND", "noninteractive");
+            vec![update, install]
+        } else if Path::new("/usr/bin/pacman").exists() {
+            let mut c = Command::new("pacman");
+            c.args(["-Sy", "--noconfirm", "--needed", "--"]).args(packages);
+            vec![c]
+        } else if Path::new("/usr/bin/zypper").exists() {
+            let mut c = Command::new("zypper");
+            c.args(["--non-interactive", "install", "--"]).args(packages);
+            vec![c]
+        } else if Path::new("/sbin/apk").exists() {
+            let mut c = Command::new("apk");
+            c.args(["add", "--"]).args(packages);
+            vec![c]
+        } else {
+            return None;
+        })
+    }
+
+    /// Offer to install a missing shell, or just do it, depending on
+    /// `mode`.  Returns true if it was installed.
+    fn install_shell(wanted: &str, mode: &str) -> Fallible<bool> {
+        let name = wanted.rsplit('/').next().unwrap_or(wanted).to_string();
+        let declined = std::fs::read_to_string(SHELLS_DECLINED).unwrap_or_default();
+        if mode == "never" || declined.lines().any(|l| l == name) {
+            return Ok(false);
+        }
+        let steps = match package_install_steps(std::slice::from_ref(&name)) {
+            Some(steps) => steps,
+            None => return Ok(false),
+        };
+        if mode == "ask" {
+            if !nix::unistd::isatty(0).unwrap_or(false) {
+                return Ok(false);
+            }
+            if !super::confirm(&format!("{} is not installed in this toolbox; install it?", name))? {
+                std::fs::create_dir_all(Path::new(SHELLS_DECLINED).parent().unwrap())?;
+                let mut f = std::fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(SHELLS_DECLINED)?;
+                writeln!(f, "{}", name)?;
+                return Ok(false);
+            }
+        }
+        eprintln!("Installing {}", name);
+        for mut step in steps {
+            if let Err(e) = step.run() {
+                eprintln!("warning: installing {} failed: {}", name, e);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Where ncurses finds the terminfo entry for `term` under `dir`,
+    /// if it's there: in a directory named by its first letter, or on
+    /// some systems the hex code of it.
+    fn find_terminfo(dir: &str, term: &str) -> Option<PathBuf> {
+        let first = term.chars().next()?;
+        [first.to_string(), format!("{:x}", first as u32)]
+            .iter()
+            .map(|d| Path::new(dir).join(d).join(term))
+            .find(|p| p.exists())
+    }
+
+    fn have_terminfo(term: &str) -> bool {
+        TERMINFO_DIRS.iter().any(|d| find_terminfo(d, term).is_some())
+    }
+
+    /// Make sure the image has the terminfo entry for $TERM, copying
+    /// the host's if need be, for newer terminals like xterm-kitty or
+    /// foot.  Returns a more common $TERM to use if we can't.
+    fn ensure_terminfo() -> Fallible<Option<String>> {
+        let term = match std::env::var("TERM") {
+            Ok(ref t) if !t.is_empty() && !t.contains('/') => t.clone(),
+            _ => return Ok(None),
+        };
+        if have_terminfo(&term) {
+            return Ok(None);
+        }
+        let host_entry = TERMINFO_DIRS
+            .iter()
+            .find_map(|d| find_terminfo(&format!("/host{}", d), &term));
+        if let Some(src) = host_entry {
+            // /etc/terminfo is searched first everywhere.
+            let first = term.chars().next().unwrap();
+            let dest = Path::new("/etc/terminfo").join(first.to_string());
+            std::fs::create_dir_all(&dest)?;
+            std::fs::copy(&src, dest.join(&term))
+                .with_context(|e| format!("Copying terminfo for {}: {}", term, e))?;
+            log(&format!("Copied terminfo for {} from the host", term));
+            return Ok(None);
+        }
+        let fallback = if have_terminfo("xterm-256color") { "xterm-256color" } else { "xterm" };
+        eprintln!(
+            "warning: no terminfo for {} in the toolbox or on the host; using {}",
+            term, fallback
+        );
+        Ok(Some(fallback.to_string()))
+    }
+
+    /// Normalize a locale name as glibc does for `locale -a`, e.g.
+    /// en_US.UTF-8 to en_US.utf8.
+    fn normalize_locale(name: &str) -> String {
+        match name.split_once('.') {
+            Some((lang, codeset)) => {
+                let (codeset, modifier) = match codeset.split_once('@') {
+                    Some((c, m)) => (c, format!("@{}", m)),
+                    None => (codeset, String::new()),
+                };
+                let codeset: String = codeset
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric())
+                    .map(|c| c.to_ascii_lowercase())
+                    .collect();
+                format!("{}.{}{}", lang, codeset, modifier)
+            }
+            None => name.to_string(),
+        }
+    }
+
+    /// Whether the image has the locale; if we can't tell, e.g. with
+    /// musl, assume so.
+    fn locale_available(name: &str) -> bool {
+        if name == "C" || name == "POSIX" {
+            return true;
+        }
+        let out = match Command::new("locale").arg("-a").stderr(Stdio::null()).output() {
+            Ok(ref out) if out.status.success() => String::from_utf8_lossy(&out.stdout).into_owned(),
+            _ => return true,
+        };
+        let name = normalize_locale(name);
+        out.lines().any(|l| normalize_locale(l) == name)
+    }
+
+    /// Try to add the locale to the image: from a glibc langpack on
+    /// Fedora and the like, otherwise with localedef.
+    fn generate_locale(name: &str) -> bool {
+        let (lang, codeset) = name.split_once('.').unwrap_or((name, "UTF-8"));
+        let (codeset, modifier) = match codeset.split_once('@') {
+            Some((c, m)) => (c, format!("@{}", m)),
+            None => (codeset, String::new()),
+        };
+        let language = lang.split('_').next().unwrap_or(lang);
+        let dnf = ["/usr/bin/dnf", "/usr/bin/microdnf"]
+            .iter()
+            .find(|p| Path::new(p).exists());
+        let r = if let Some(dnf) = dnf {
+            Command::new(dnf)
+                .args(["-y", "install"])
+                .arg(format!("glibc-langpack-{}", language))
+                .stdout(Stdio::null())
+                .run()
+        } else {
+            let charmap = if normalize_locale(name).ends_with(".utf8") { "UTF-8" } else { codeset };
+            Command::new("localedef")
+                .arg("-i")
+                .arg(format!("{}{}", lang, modifier))
+                .args(["-f", charmap, name])
+                .run()
+        };
+        r.is_ok()
+    }
+
+    /// Make sure the session's locale ($LANG) exists in the image,
+    /// generating it if need be, to avoid warnings from perl, glibc
+    /// and so on.  Returns the locale to use instead if that fails.
+    fn ensure_locale() -> Fallible<Option<String>> {
+        let lang = match std::env::var("LANG") {
+            Ok(ref l) if !l.is_empty() => l.clone(),
+            _ => return Ok(None),
+        };
+        if locale_available(&lang) {
+            return Ok(None);
+        }
+        let fallback = if locale_available("C.UTF-8") { "C.UTF-8" } else { "C" };
+        // Only warn the first time.
+        let unavailable = std::fs::read_to_string(LOCALES_UNAVAILABLE).unwrap_or_default();
+        if !unavailable.lines().any(|l| l == lang) {
+            eprintln!("Generating locale {}", lang);
+            if generate_locale(&lang) && locale_available(&lang) {
+                return Ok(None);
+            }
+            std::fs::create_dir_all(Path::new(LOCALES_UNAVAILABLE).parent().unwrap())?;
+            let mut f = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(LOCALES_UNAVAILABLE)?;
+            writeln!(f, "{}", lang)?;
+            eprintln!(
+                "warning: locale {} is not available in the toolbox; using {}",
+                lang, fallback
+            );
+        }
+        Ok(Some(fallback.to_string()))
+    }
+
+    /// How to install a package with the image's package manager.
+    fn install_hint() -> Option<&'static str> {
+        [
+            ("/usr/bin/dnf", "sudo dnf install PACKAGE"),
+            ("/usr/bin/microdnf", "sudo microdnf install PACKAGE"),
+            ("/usr/bin/apt-get", "sudo apt-get install PACKAGE"),
+            ("/usr/bin/pacman", "sudo pacman -S PACKAGE"),
+            ("/usr/bin/zypper", "sudo zypper install PACKAGE"),
+            ("/sbin/apk", "sudo apk add PACKAGE"),
+        ]
+        .iter()
+        .find(|(p, _)| Path::new(p).exists())
+        .map(|(_, hint)| *hint)
+    }
+
+    /// Tell a new user of the toolbox what it is and how to use it.
+    fn print_motd(state: &EntrypointState) {
+        match (live_name(state), &state.image) {
+            (Some(name), Some(image)) => println!("Welcome to toolbox {}, from {}.", name, image),
+            _ => println!("Welcome to your toolbox."),
+        }
+        if let Some(hint) = install_hint() {
+            println!("Install software with `{}`.", hint);
+        }
+        if state.isolated_home {
+            println!("This toolbox has its own home directory; the host's files are under /host.");
+        } else {
+            println!("Your home directory is shared with the host; other files are under /host.");
+        }
+        println!("Run commands on the host with `toolbox host-exec COMMAND`.");
+        println!();
+    }
+
+    /// Replace host commands in the toolbox's PATH with scripts that
+    /// run them on the host via host-exec, where we can, and remove
+    /// ours for commands no longer listed.
+    fn install_host_shims(state: &EntrypointState) -> Fallible<()> {
+        let commands: Vec<String> = match state.host_commands {
+            Some(ref commands) => commands.clone(),
+            None => super::HOST_COMMANDS.iter().map(|c| c.to_string()).collect(),
+        };
+        let is_ours = |p: &Path| {
+            std::fs::read_to_string(p)
+                .map(|c| c.lines().nth(1) == Some(HOST_SHIM_MARKER))
+                .unwrap_or(false)
+        };
+        std::fs::create_dir_all(HOST_SHIM_DIR)?;
+        for e in std::fs::read_dir(HOST_SHIM_DIR)? {
+            let e = e?;
+            let name = e.file_name().to_string_lossy().into_owned();
+            if !commands.contains(&name) && is_ours(&e.path()) {
+                std::fs::remove_file(e.path())?;
+            }
+        }
+        for c in &commands {
+            if c.is_empty() || c.contains('/') {
+                continue;
+            }
+            let path = Path::new(HOST_SHIM_DIR).join(c);
+            // Leave alone whatever the image has there.
+            if path.exists() && !is_ours(&path) {
+                continue;
+            }
+            std::fs::write(&path, HOST_SHIM.replace("@COMMAND@", c))?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+
+    /// Run the configured init commands as the user, on every start.
+    /// Like the post-create hook, they can't stop the toolbox being
+    /// used.
+    fn run_init_commands(commands: &[String], username: &str) {
+        for c in commands {
+            log(&format!("Running init command: {}", c));
+            if let Err(e) = Command::new("su").args(["-", username, "-c", c.as_str()]).run() {
+                log(&format!("warning: init command {}: {}", c, e));
+                eprintln!("warning: init command {} failed: {}", c, e);
+            }
+        }
+    }
+
+    /// Run the post-create hook as the user, once per container; it's
+    /// not retried if it fails, since it may have done half its work.
+    fn run_post_create(hook: &str, username: &str) -> Fallible<()> {
+        if Path::new(POST_CREATE_DONE).exists() {
+            return Ok(());
+        }
+        std::fs::write(POST_CREATE_HOOK, hook)?;
+        std::fs::set_permissions(POST_CREATE_HOOK, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::File::create(POST_CREATE_DONE)?;
+        log("Running post-create hook");
+        if let Err(e) = Command::new("su").args(["-", username, "-c", POST_CREATE_HOOK]).run() {
+            log(&format!("warning: post-create hook: {}", e));
+            eprintln!("warning: post-create hook failed: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Find `wanted` in the image, as given or by name in /usr/bin
+    /// and /bin.
+    fn find_shell(wanted: &str) -> Option<String> {
+        let name = wanted.rsplit('/').next().unwrap_or(wanted);
+        let candidates = [
+            wanted.to_string(),
+            format!("/usr/bin/{}", name),
+            format!("/bin/{}", name),
+        ];
+        let mut candidates = candidates.iter().filter(|p| p.starts_with('/'));
+        candidates.find(|p| is_executable(p)).cloned()
+    }
+
+    fn is_executable(path: &str) -> bool {
+        std::fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    /// The first of `wanted` (the configured shell, then $SHELL) that
+    /// the image has, or else bash or sh.  Say so if that isn't the
+    /// first one.
+    fn choose_shell(wanted: &[String]) -> Fallible<String> {
+        let fallbacks = ["/bin/bash".to_string(), "/bin/sh".to_string()];
+        let (shell, requested) = wanted
+            .iter()
+            .chain(&fallbacks)
+            .find_map(|w| find_shell(w).map(|s| (s, w)))
+            .ok_or_else(|| failure::err_msg("No usable shell in the toolbox"))?;
+        if let Some(first) = wanted.first() {
+            if first != requested {
+                let missing: Vec<&str> = wanted
+                    .iter()
+                    .take_while(|w| *w != requested)
+                    .map(|w| w.as_str())
+                    .collect();
+                eprintln!(
+                    "warning: {} not found in the toolbox; using {}",
+                    missing.join(", "),
+                    shell
+                );
+            }
+        }
+        Ok(shell)
+    }
+
+    /// Where the host directory `dir` is in the toolbox: the same path
+    /// within the bind mounted home directory, otherwise under /host.
+    fn container_workdir(dir: &str, state: &EntrypointState) -> Option<String> {
+        let in_home = !state.isolated_home && Path::new(dir).starts_with(&state.home);
+        let path = if in_home { dir.to_string() } else { format!("/host{}", dir) };
+        if Path::new(&path).is_dir() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// A host socket path as seen in the toolbox, if it isn't already
+    /// at the same path: most of the host is under /host.
+    fn container_socket_path(path: &str) -> Option<String> {
+        if !path.starts_with('/') || Path::new(path).exists() {
+            return None;
+        }
+        let host_path = format!("/host{}", path);
+        if Path::new(&host_path).exists() {
+            Some(host_path)
+        } else {
+            None
+        }
+    }
+
+    /// Forwarded variables naming host sockets, rewritten to where the
+    /// sockets are in the toolbox where that differs.
+    fn host_socket_env() -> Vec<(&'static str, String)> {
+        let mut r = Vec::new();
+        if let Ok(sock) = std::env::var("SSH_AUTH_SOCK") {
+            if let Some(p) = container_socket_path(&sock) {
+                r.push(("SSH_AUTH_SOCK", p));
+            }
+        }
+        // E.g. unix:path=/run/user/1000/bus, possibly a ;-separated
+        // list of addresses with ,-separated key=value pairs.
+        if let Ok(addr) = std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+            let rewritten: Vec<String> = addr
+                .split(';')
+                .map(|a| match a.strip_prefix("unix:") {
+                    Some(params) => {
+                        let params: Vec<String> = params
+                            .split(',')
+                            .map(|kv| match kv.strip_prefix("path=") {
+                                Some(p) => match container_socket_path(p) {
+                                    Some(p) => format!("path={}", p),
+                                    None => kv.to_string(),
+                                },
+                                None => kv.to_string(),
+                            })
+                            .collect();
+                        format!("unix:{}", params.join(","))
+                    }
+                    None => a.to_string(),
+                })
+                .collect();
+            let rewritten = rewritten.join(";");
+            if rewritten != addr {
+                r.push(("DBUS_SESSION_BUS_ADDRESS", rewritten));
+            }
+        }
+        r
+    }
+
+    /// Append a message to the stderr of PID 1, which podman captures
+    /// as the container log; see `coretoolbox logs`.
+    fn log(msg: &str) {
+        if let Ok(mut f) = std::fs::OpenOptions::new()
+            .append(true)
+            .open("/proc/1/fd/2")
+        {
+            let _ = writeln!(f, "coretoolbox: {}", msg);
+        }
+    }
+
+    pub(crate) fn exec(opts: &super::ExecOpts) -> Fallible<()> {
         if !super::in_container() {
             bail!("Not inside a container");
         }
-        init_container().with_context(|e| format!("Initializing container: {}", e))?;
+        // Other than with --init-only, we only return on failure;
+        // make sure that doesn't get lost.
+        let r = exec_inner(opts);
+        if let Err(ref e) = r {
+            log(&format!("error: {}", e));
+        }
+        r
+    }
+
+    fn exec_inner(opts: &super::ExecOpts) -> Fallible<()> {
+        use nix::sys::stat::Mode;
+        init_container(&opts.init_commands)
+            .with_context(|e| format!("Initializing container: {}", e))?;
         let initstamp = Path::new(CONTAINER_INITIALIZED_STAMP);
         if !initstamp.exists() {
             bail!("toolbox not initialized");
         }
+        if opts.init_only {
+            return Ok(());
+        }
         // Set a sane umask (022) by default; something seems to be setting it to 077
         nix::sys::stat::umask(Mode::S_IWGRP & Mode::S_IWOTH);
-        let username = super::getenv_required_utf8("USER")?;
-        let su_preserved_env_arg =
-            format!("--whitelist-environment={}", super::PRESERVED_ENV.join(","));
-        Err(Command::new("setpriv")
-            .args(&[
-                "--inh-caps=-all",
-                "su",
-                su_preserved_env_arg.as_str(),
-                "-",
-                &username,
-            ])
+        let username = if opts.root {
+            "root".to_string()
+        } else {
+            super::getenv_required_utf8("USER")?
+        };
+        let mut preserved: Vec<String> =
+            super::PRESERVED_ENV.iter().map(|s| s.to_string()).collect();
+        if let Ok(extra) = std::env::var("TOOLBOX_PRESERVED_ENV") {
+            preserved.extend(extra.split(',').filter(|s| !s.is_empty()).map(String::from));
+        }
+        let state = saved_state()?;
+        let container_host = match (&state.container_host, std::env::var_os("CONTAINER_HOST")) {
+            (Some(url), None) => Some(url.clone()),
+            _ => None,
+        };
+        if container_host.is_some() {
+            preserved.push("CONTAINER_HOST".to_string());
+        }
+        let unset = std::env::var("TOOLBOX_UNSET_ENV").unwrap_or_default();
+        let unset: Vec<&str> = unset.split(',').filter(|s| !s.is_empty()).collect();
+        preserved.retain(|n| !unset.contains(&n.as_str()));
+        // su - starts in the home directory, so change directory in
+        // the login shell, then exec the command or interactive shell;
+        // the directory and arguments are passed through as positional
+        // parameters.
+        let (shell, script) = if opts.command.is_empty() {
+            if let Some(wanted) = opts.shells.first() {
+                if find_shell(wanted).is_none() {
+                    install_shell(wanted, &opts.install_shell)?;
+                }
+            }
+            let shell = choose_shell(&opts.shells)?;
+            (Some(shell), "cd -- \"$1\"; SHELL=\"$2\"; export SHELL; exec \"$2\" -l")
+        } else {
+            (None, "cd -- \"$1\"; shift; exec \"$@\"")
+        };
+        let mut cmd = Command::new("setpriv");
+        if state.keep_groups && !opts.root {
+            // The groups podman kept aren't mapped, so once su's
+            // initgroups() dropped them they couldn't be set again;
+            // switch user without touching them, and set up the
+            // environment as su - would.
+            cmd.args([
+                format!("--reuid={}", state.uid),
+                format!("--regid={}", state.gid.unwrap_or(state.uid)),
+            ]);
+            cmd.args(["--keep-groups", "--inh-caps=-all", "/bin/sh", "-c", script]);
+            let env: Vec<(String, std::ffi::OsString)> = preserved
+                .iter()
+                .filter_map(|n| std::env::var_os(n).map(|v| (n.clone(), v)))
+                .collect();
+            cmd.env_clear().envs(env);
+            cmd.env("USER", &username)
+                .env("LOGNAME", &username)
+                .env("PATH", "/usr/local/bin:/usr/bin:/bin");
+        } else {
+            let su_preserved_env_arg =
+                format!("--whitelist-environment={}", preserved.join(","));
+            cmd.args(["--inh-caps=-all", "su", su_preserved_env_arg.as_str()]);
+            // Run the wrapper with sh, whatever the shell's syntax.
+            if shell.is_some() {
+                cmd.args(["--shell", "/bin/sh"]);
+            }
+            cmd.args(["-c", script, "-", &username, "--"]);
+        }
+        // su sets HOME from /etc/passwd, which adduser keeps in line
+        // with the host; make sure it's there to start in.
+        let home = if opts.root {
+            "/root".to_string()
+        } else {
+            if !Path::new(&state.home).is_dir() {
+                std::fs::create_dir_all(&state.home)?;
+                let uid = nix::unistd::Uid::from_raw(state.uid);
+                let gid = nix::unistd::Gid::from_raw(state.gid.unwrap_or(state.uid));
+                nix::unistd::chown(state.home.as_str(), Some(uid), Some(gid))?;
+            }
+            state.home.clone()
+        };
+        cmd.env("HOME", &home).current_dir(&home);
+        let workdir = opts
+            .workdir
+            .as_ref()
+            .and_then(|d| container_workdir(d, &state))
+            .unwrap_or_else(|| home.clone());
+        cmd.args(["toolbox", workdir.as_str()]);
+        match shell {
+            Some(ref shell) => cmd.arg(shell),
+            None => cmd.args(&opts.command),
+        };
+        for (name, value) in host_socket_env() {
+            cmd.env(name, value);
+        }
+        if let Some(url) = container_host {
+            cmd.env("CONTAINER_HOST", url);
+        }
+        if let Some(lang) = ensure_locale()? {
+            cmd.env("LANG", lang);
+        }
+        if let Some(term) = ensure_terminfo()? {
+            cmd.env("TERM", term);
+        }
+        // The container may have been renamed since it was started.
+        if let Err(e) = write_toolbox_env(&state) {
+            log(&format!("warning: writing {}: {}", TOOLBOX_ENV, e));
+        }
+        if opts.motd && shell.is_some() && !Path::new(MOTD_SHOWN).exists() {
+            print_motd(&state);
+            std::fs::create_dir_all(Path::new(MOTD_SHOWN).parent().unwrap())?;
+            std::fs::File::create(MOTD_SHOWN)?;
+        }
+        log(&format!("Starting session for {}: {:?}", username, opts.command));
+        for n in &unset {
+            cmd.env_remove(n);
+        }
+        Err(cmd
+            .env_remove("TOOLBOX_STATE")
             .env_remove("TOOLBOX_STATEFILE")
+            .env_remove("TOOLBOX_SECRETS")
+            .env_remove("TOOLBOX_PRESERVED_ENV")
+            .env_remove("TOOLBOX_UNSET_ENV")
             .exec()
             .into())
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_normalize_locale() {
+            assert_eq!(normalize_locale("en_US.UTF-8"), "en_US.utf8");
+            assert_eq!(normalize_locale("de_DE.ISO-8859-1"), "de_DE.iso88591");
+            assert_eq!(normalize_locale("sr_RS.UTF-8@latin"), "sr_RS.utf8@latin");
+            assert_eq!(normalize_locale("C"), "C");
+            assert_eq!(normalize_locale("en_US"), "en_US");
+        }
+
+        #[test]
+        fn test_match_groups() {
+            let group = |n: &str, g| (n.to_string(), g);
+            let existing = vec![group("wheel", 10), group("video", 39), group("render", 998)];
+            let host = vec![
+                // Joined under the image's gid
+                group("video", 44),
+                // Created with the host's gid
+                group("dialout", 18),
+                // Skipped: the gid is taken by another group
+                group("docker", 998),
+            ];
+            let (create, join) = match_groups(&existing, &host);
+            assert_eq!(create, vec![("dialout", 18)]);
+            assert_eq!(join, vec!["video", "dialout"]);
+            assert_eq!(match_groups(&existing, &[]), (vec![], vec![]));
+        }
+
+        #[test]
+        fn test_group_with_members() {
+            let group = "wheel:x:10:\nvideo:x:39:bob\naudio:x:63:alice\nusers:x:100:";
+            let cases: &[(&[&str], &str)] = &[
+                // Appended to existing members, or none
+                (
+                    &["wheel", "video"],
+                    "wheel:x:10:alice\nvideo:x:39:bob,alice\naudio:x:63:alice\nusers:x:100:\n",
+                ),
+                // Already a member
+                (&["audio"], "wheel:x:10:\nvideo:x:39:bob\naudio:x:63:alice\nusers:x:100:\n"),
+                // Missing groups are left alone
+                (&["dialout"], "wheel:x:10:\nvideo:x:39:bob\naudio:x:63:alice\nusers:x:100:\n"),
+            ];
+            for (groups, expected) in cases {
+                assert_eq!(group_with_members(group, "alice", groups), *expected, "{:?}", groups);
+            }
+            // The file gets a trailing newline either way.
+            assert_eq!(
+                group_with_members("wheel:x:10:\n", "alice", &["wheel"]),
+                "wheel:x:10:alice\n"
+            );
+        }
+
+        #[test]
+        fn test_shadow_with_password() {
+            let shadow = "root:!::0:99999:7:::\nalice:!!:19000:0:99999:7:::";
+            let cases = &[
+                // Existing user: only the password changes
+                (
+                    shadow,
+                    "$6$salt$hash",
+                    "root:!::0:99999:7:::\nalice:$6$salt$hash:19000:0:99999:7:::\n",
+                ),
+                // Locking
+                (shadow, "!", "root:!::0:99999:7:::\nalice:!:19000:0:99999:7:::\n"),
+                // Missing user: appended
+                (
+                    "root:!::0:99999:7:::\n",
+                    "$6$salt$hash",
+                    "root:!::0:99999:7:::\nalice:$6$salt$hash:20000::::::\n",
+                ),
+                // No /etc/shadow yet
+                ("", "!", "alice:!:20000::::::\n"),
+            ];
+            for (contents, hash, expected) in cases {
+                assert_eq!(shadow_with_password(contents, "alice", hash, 20000), *expected);
+            }
+            // Only an exact name matches.
+            assert_eq!(
+                shadow_with_password("alice2:x:1::::::\n", "alice", "!", 20000),
+                "alice2:x:1::::::\nalice:!:20000::::::\n"
+            );
+        }
+
+        #[test]
+        fn test_passwd_with_home() {
+            let passwd = "root:x:0:0:root:/root:/bin/bash\nalice:x:1000:1000::/home/alice:/bin/sh";
+            assert_eq!(
+                passwd_with_home(passwd, "alice", "/var/home/alice").as_deref(),
+                Some(
+                    "root:x:0:0:root:/root:/bin/bash\n\
+                     alice:x:1000:1000::/var/home/alice:/bin/sh\n"
+                )
+            );
+            // Already right
+            assert_eq!(passwd_with_home(passwd, "alice", "/home/alice"), None);
+            // Missing user, or only a prefix of the name
+            assert_eq!(passwd_with_home(passwd, "bob", "/home/bob"), None);
+            assert_eq!(passwd_with_home(passwd, "ali", "/home/ali"), None);
+            // Malformed entries are left alone.
+            assert_eq!(passwd_with_home("alice:x:1000\n", "alice", "/home/alice"), None);
+        }
+    }
+}
+
+fn run_main() -> Fallible<()> {
+    let cli = Cli::from_args();
+    // Podman in the toolbox, if any, doesn't have our containers.
+    if in_toolbox() && cli.cmd.runs_on_host() {
+        return forward_to_host();
+    }
+    OFFLINE.store(cli.offline, std::sync::atomic::Ordering::Relaxed);
+    // Report a broken configuration file up front.
+    config()?;
+    match cli.cmd {
+        Opt::Create(mut opts) => {
+            // Same as --podman-arg, just easier to type.
+            opts.create.podman_args.append(&mut opts.trailing_podman_args);
+            cmd_create(&opts.create)?;
+            if opts.start && !opts.create.show_command {
+                start_detached(&opts.create.container_name())?;
+            }
+            Ok(())
+        }
+        Opt::Run(ref runopts) => run(runopts),
+        Opt::Enter(ref opts) => enter(opts),
+        Opt::Exec(ref opts) => entrypoint::exec(opts),
+        Opt::List => list(),
+        Opt::Stop(ref opts) => stop(opts),
+        Opt::Upgrade(ref opts) => upgrade(opts),
+        Opt::Status(ref opts) => status(opts),
+        Opt::Reset(ref opts) => reset(opts),
+        Opt::Rename(ref opts) => rename(opts),
+        Opt::Logs(ref opts) => logs(opts),
+        Opt::Ps(ref opts) => ps(opts),
+        Opt::Stats(ref opts) => stats(opts),
+        Opt::Gc(ref opts) => gc(opts),
+        Opt::Du => du(),
+        Opt::Prune(ref opts) => prune(opts),
+        Opt::Export(ref opts) => export(opts),
+        Opt::Load(ref opts) => load(opts),
+        Opt::Commit(ref opts) => commit(opts),
+        Opt::Build(ref opts) => build(opts),
+        Opt::InitImage(ref opts) => init_image(opts),
+        Opt::Cp(ref opts) => cp(opts),
+        Opt::Mount(ref opts) => mount(opts),
+        Opt::Checkpoint(ref opts) => checkpoint(opts),
+        Opt::Restore(ref opts) => restore(opts),
+        Opt::Doctor => doctor(),
+        Opt::Generate(GenerateOpts::Systemd(ref opts)) => generate_systemd(opts),
+        Opt::Completion(ref opts) => completion(opts),
+        Opt::Man(ref opts) => man(opts),
+        Opt::Search(ref opts) => search(opts),
+        Opt::CheckUpdate => check_update(),
+        Opt::Rm(mut opts) => {
+            opts.containers.append(&mut opts.legacy_names);
+            rm(&opts)
+        }
+        Opt::Rmi(ref opts) => rmi(opts),
+        Opt::HostExec(ref opts) => host_exec(opts),
+        Opt::RunPid1 => run_pid1(),
+    }
 }
 
 /// Primary entrypoint
 fn main() {
-    || -> Fallible<()> {
-        let opts = Opt::from_args();
-         match opts {
-            Opt::Run(ref runopts) => run(runopts),
-            Opt::Exec => entrypoint::exec(),
-            Opt::Rm(ref opts) => rm(opts),
-            Opt::RunPid1 => run_pid1(opts),
-        }
-    }()
-    .unwrap_or_else(|e| {
+    run_main().unwrap_or_else(|e| {
         eprintln!("error: {}", e);
         std::process::exit(1)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_volume() {
+        std::env::set_var("CORETOOLBOX_TEST_DIR", "/srv/data");
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(expand_volume("/srv:/srv").unwrap(), "/srv:/srv");
+        assert_eq!(expand_volume("~/src").unwrap(), format!("{}/src", home));
+        assert_eq!(expand_volume("~:/data").unwrap(), format!("{}:/data", home));
+        assert_eq!(expand_volume("~user/src").unwrap(), "~user/src");
+        assert_eq!(
+            expand_volume("$CORETOOLBOX_TEST_DIR:/data").unwrap(),
+            "/srv/data:/data"
+        );
+        assert_eq!(
+            expand_volume("${CORETOOLBOX_TEST_DIR}/x:/x").unwrap(),
+            "/srv/data/x:/x"
+        );
+        assert!(expand_volume("${CORETOOLBOX_TEST_DIR:/x").is_err());
+        assert!(expand_volume("$:/x").is_err());
+        assert!(expand_volume("$CORETOOLBOX_TEST_UNSET:/x").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_assignment() {
+        assert_eq!(
+            parse_env_assignment("FOO=bar").unwrap(),
+            ("FOO".to_string(), "bar".to_string())
+        );
+        assert_eq!(
+            parse_env_assignment("FOO=a=b").unwrap(),
+            ("FOO".to_string(), "a=b".to_string())
+        );
+        assert_eq!(
+            parse_env_assignment("FOO=").unwrap(),
+            ("FOO".to_string(), String::new())
+        );
+        assert!(parse_env_assignment("FOO").is_err());
+        assert!(parse_env_assignment("=bar").is_err());
+    }
+
+    #[test]
+    fn test_read_subid_range() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        writeln!(f, "bob:100000:65536\nalice:165536:65536\n1002:231072:1000").unwrap();
+        let path = f.path().to_owned();
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            read_subid_range(path, "alice", 1001).unwrap(),
+            Some((165536, 65536))
+        );
+        assert_eq!(
+            read_subid_range(path, "carol", 1002).unwrap(),
+            Some((231072, 1000))
+        );
+        assert_eq!(read_subid_range(path, "dave", 1003).unwrap(), None);
+        assert_eq!(
+            read_subid_range("/nonexistent/subuid", "alice", 1001).unwrap(),
+            None
+        );
+        writeln!(f, "dave:x:65536").unwrap();
+        assert!(read_subid_range(path, "dave", 1003).is_err());
+    }
+
+    #[test]
+    fn test_id_mappings() {
+        assert_eq!(
+            id_mappings("--uidmap", 1000, 65536),
+            vec![
+                "--uidmap=1000:0:1",
+                "--uidmap=0:1:1000",
+                "--uidmap=1001:1001:64536",
+            ]
+        );
+        // Only enough subordinate ids for those below ours.
+        assert_eq!(
+            id_mappings("--gidmap", 1000, 1000),
+            vec!["--gidmap=1000:0:1", "--gidmap=0:1:1000"]
+        );
+    }
+
+    #[test]
+    fn test_parse_podman_version() {
+        assert_eq!(parse_podman_version("podman version 1.4.4\n").unwrap(), (1, 4, 4));
+        assert_eq!(parse_podman_version("podman version 1.6.0-rc1").unwrap(), (1, 6, 0));
+        assert_eq!(parse_podman_version("podman version 4.9.4-dev").unwrap(), (4, 9, 4));
+        assert_eq!(parse_podman_version("podman version 5.0").unwrap(), (5, 0, 0));
+        assert!(parse_podman_version("podman version 1.4.4").unwrap() < KEEP_ID_PODMAN_VERSION);
+        assert!(parse_podman_version("").is_err());
+    }
+
+    #[test]
+    fn test_split_registry() {
+        assert_eq!(
+            split_registry("registry.fedoraproject.org/f30/fedora-toolbox:30"),
+            Some(("registry.fedoraproject.org", "f30/fedora-toolbox:30"))
+        );
+        assert_eq!(
+            split_registry("localhost:5000/mytoolbox"),
+            Some(("localhost:5000", "mytoolbox"))
+        );
+        assert_eq!(
+            split_registry("localhost/mytoolbox:latest"),
+            Some(("localhost", "mytoolbox:latest"))
+        );
+        assert_eq!(split_registry("fedora/fedora:30"), None);
+        assert_eq!(split_registry("fedora:30"), None);
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("--volume=/srv:/srv:rslave"), "--volume=/srv:/srv:rslave");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_parse_cp_arg() {
+        assert_eq!(
+            parse_cp_arg("mytoolbox:/etc/hosts"),
+            (Some("mytoolbox".to_string()), "/etc/hosts")
+        );
+        assert_eq!(parse_cp_arg("/tmp/a:b"), (None, "/tmp/a:b"));
+        assert_eq!(parse_cp_arg("./a:b"), (None, "./a:b"));
+        assert_eq!(parse_cp_arg("notes.txt"), (None, "notes.txt"));
+    }
+
+    #[test]
+    fn test_pull_error_is_transient() {
+        assert!(pull_error_is_transient(
+            "Error: error pinging docker registry quay.io: Get https://quay.io/v2/: dial tcp: i/o timeout"
+        ));
+        assert!(pull_error_is_transient("read: connection reset by peer"));
+        assert!(!pull_error_is_transient(
+            "Error: unable to pull quay.io/nope: manifest unknown: manifest unknown"
+        ));
+        assert!(!pull_error_is_transient(
+            "Error: Unauthorized: access to the requested resource is not authorized"
+        ));
+        assert!(!pull_error_is_transient(
+            "Source image rejected: Running image docker://quay.io/x is rejected by policy."
+        ));
+    }
+
+    #[test]
+    fn test_rm_and_stop_use_env_container() {
+        std::env::set_var("CORETOOLBOX_CONTAINER", "mybox");
+        let names = |args: &[&str]| match Cli::from_iter(args).cmd {
+            Opt::Rm(opts) => names_or_default(&opts.containers, &opts.names),
+            Opt::Stop(opts) => names_or_default(&opts.containers, &opts.names),
+            cmd => panic!("unexpected {:?}", cmd),
+        };
+        assert_eq!(names(&["coretoolbox", "rm"]), vec!["mybox"]);
+        assert_eq!(names(&["coretoolbox", "stop"]), vec!["mybox"]);
+        // The command line still wins.
+        assert_eq!(names(&["coretoolbox", "rm", "-c", "other"]), vec!["other"]);
+        assert_eq!(names(&["coretoolbox", "stop", "other"]), vec!["other"]);
+        std::env::remove_var("CORETOOLBOX_CONTAINER");
+    }
+
+    #[test]
+    fn test_check_package_name() {
+        for ok in &["git", "python3-devel", "gcc-c++", "perl(Foo::Bar)", "vim-enhanced.x86_64"] {
+            assert!(check_package_name(ok).is_ok(), "{}", ok);
+        }
+        for bad in &[
+            "",
+            "--setopt=reposdir=/tmp",
+            "-y",
+            "https://example.com/evil.rpm",
+            "./evil.rpm",
+            "/tmp/evil.rpm",
+        ] {
+            assert!(check_package_name(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_state_omits_secrets() {
+        let state: EntrypointState = serde_json::from_str(
+            r#"{"username": "alice", "uid": 1000, "home": "/home/alice",
+                "ostree_based_host": false, "post_create": "echo hi",
+                "password_hash": "$6$salt$hash"}"#,
+        )
+        .unwrap();
+        // Older containers passed them in the state.
+        assert_eq!(state.post_create.as_deref(), Some("echo hi"));
+        assert_eq!(state.password_hash.as_deref(), Some("$6$salt$hash"));
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(!json.contains("post_create"), "{}", json);
+        assert!(!json.contains("password_hash"), "{}", json);
+    }
+
+    #[test]
+    fn test_config_snake_case_keys() {
+        let config: Config = toml::from_str(
+            r#"
+            init_commands = ["echo hi"]
+            extra_podman_args = ["--cap-add=SYS_PTRACE"]
+            default_shell = "zsh"
+            passwordless-sudo = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.init_commands, vec!["echo hi"]);
+        assert_eq!(config.extra_podman_args, vec!["--cap-add=SYS_PTRACE"]);
+        assert_eq!(config.default_shell.as_deref(), Some("zsh"));
+        assert_eq!(config.passwordless_sudo, Some(false));
+        assert!(toml::from_str::<Config>("no_such_key = 1").is_err());
+    }
+
+    #[test]
+    fn test_legacy_name_option() {
+        match Cli::from_iter(&["coretoolbox", "create", "-n", "foo"]).cmd {
+            Opt::Create(opts) => assert_eq!(opts.create.container_name(), "foo"),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+        match Cli::from_iter(&["coretoolbox", "rm", "-n", "foo", "--name", "bar"]).cmd {
+            Opt::Rm(opts) => assert_eq!(opts.legacy_names, vec!["foo", "bar"]),
+            cmd => panic!("unexpected {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn test_systemd_quote() {
+        assert_eq!(systemd_quote("echo hi"), r#""echo hi""#);
+        assert_eq!(systemd_quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(systemd_quote("echo $HOME 100%"), r#""echo $$HOME 100%%""#);
+    }
+
+    #[test]
+    fn test_read_project_keeps_only_project_keys() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            f,
+            "image = \"fedora-toolbox:40\"\nname = \"web\"\nvolumes = [\"/srv:/srv\"]\n\
+             packages = [\"gcc\"]\ninit-commands = [\"touch /tmp/pwned\"]\n\
+             [env]\nLD_PRELOAD = \"/tmp/evil.so\""
+        )
+        .unwrap();
+        let config = Config::read_project(f.path()).unwrap().unwrap();
+        assert_eq!(config.image.as_deref(), Some("fedora-toolbox:40"));
+        assert_eq!(config.name.as_deref(), Some("web"));
+        assert_eq!(config.volumes, vec!["/srv:/srv"]);
+        assert!(config.packages.is_empty());
+        assert!(config.init_commands.is_empty());
+        assert!(config.env.is_empty());
+        assert!(Config::read_project(Path::new("/nonexistent/.coretoolbox.toml"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_config_merge() {
+        let parse = |s: &str| -> Config { toml::from_str(s).unwrap() };
+        let mut config = parse(
+            "image = \"fedora-toolbox:39\"\nvolumes = [\"/srv:/srv\"]\n\
+             default-shell = \"/bin/zsh\"\n[env]\nEDITOR = \"vi\"\nPAGER = \"less\"",
+        );
+        config.merge(parse(
+            "image = \"fedora-toolbox:40\"\nvolumes = [\"/data:/data\"]\noffline = true\n\
+             [env]\nEDITOR = \"emacs\"",
+        ));
+        // The later file wins for single values...
+        assert_eq!(config.image.as_deref(), Some("fedora-toolbox:40"));
+        assert_eq!(config.offline, Some(true));
+        // ...but doesn't unset what it leaves out...
+        assert_eq!(config.default_shell.as_deref(), Some("/bin/zsh"));
+        assert_eq!(config.env["PAGER"], "less");
+        assert_eq!(config.env["EDITOR"], "emacs");
+        // ...and lists are appended.
+        assert_eq!(config.volumes, vec!["/srv:/srv", "/data:/data"]);
+        config.merge(Config::default());
+        assert_eq!(config.image.as_deref(), Some("fedora-toolbox:40"));
+        assert_eq!(config.volumes.len(), 2);
+    }
+}